@@ -0,0 +1,232 @@
+use crate::vmath::*;
+use crate::shader::Varyings;
+
+
+/// A vertex in homogeneous clip space, before the perspective divide.
+#[derive(Clone)]
+pub struct ClipVertex {
+    pub position: Vec4,
+    pub varyings: Varyings,
+}
+
+impl ClipVertex {
+    #[inline]
+    pub fn new(position: Vec4, varyings: Varyings) -> Self {
+        Self { position: position, varyings: varyings }
+    }
+
+    pub fn lerp(a: &ClipVertex, b: &ClipVertex, t: f32) -> Self {
+        Self {
+            position: a.position + (b.position - a.position) * t,
+            varyings: Varyings::lerp(&a.varyings, &b.varyings, t),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClipPlane {
+    Near,
+    Far,
+    Left,
+    Right,
+    Bottom,
+    Top,
+}
+
+impl ClipPlane {
+    pub const ALL: [ClipPlane; 6] = [
+        ClipPlane::Near,
+        ClipPlane::Far,
+        ClipPlane::Left,
+        ClipPlane::Right,
+        ClipPlane::Bottom,
+        ClipPlane::Top,
+    ];
+
+    /// Signed distance of a clip space point to the plane, positive on the inside.
+    #[inline]
+    pub fn distance(self, p: Vec4) -> f32 {
+        match self {
+            ClipPlane::Near => p.z,
+            ClipPlane::Far => p.w - p.z,
+            ClipPlane::Left => p.w + p.x,
+            ClipPlane::Right => p.w - p.x,
+            ClipPlane::Bottom => p.w + p.y,
+            ClipPlane::Top => p.w - p.y,
+        }
+    }
+
+    #[inline]
+    pub const fn bit(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+/// Bitmask of the planes the point lies outside of.
+pub fn outcode(p: Vec4) -> u32 {
+    let mut code = 0;
+    for plane in ClipPlane::ALL {
+        if plane.distance(p) < 0.0 {
+            code |= plane.bit();
+        }
+    }
+
+    code
+}
+
+/// Sutherland-Hodgman clipping of a convex polygon against a single plane.
+fn clip_against_plane(plane: ClipPlane, input: &Vec<ClipVertex>, output: &mut Vec<ClipVertex>) {
+    output.clear();
+
+    if input.is_empty() {
+        return;
+    }
+
+    let mut prev = &input[input.len() - 1];
+    let mut prev_dist = plane.distance(prev.position);
+    for curr in input {
+        let curr_dist = plane.distance(curr.position);
+
+        if (prev_dist >= 0.0) != (curr_dist >= 0.0) {
+            let t = prev_dist / (prev_dist - curr_dist);
+            output.push(ClipVertex::lerp(prev, curr, t));
+        }
+        if curr_dist >= 0.0 {
+            output.push(curr.clone());
+        }
+
+        prev = curr;
+        prev_dist = curr_dist;
+    }
+}
+
+/// Clips a convex polygon against every plane in `planes` (a mask of `ClipPlane::bit`).
+/// The result replaces `polygon`, `scratch` is only used as temporary storage.
+pub fn clip_polygon(polygon: &mut Vec<ClipVertex>, scratch: &mut Vec<ClipVertex>, planes: u32) {
+    for plane in ClipPlane::ALL {
+        if planes & plane.bit() == 0 {
+            continue;
+        }
+
+        clip_against_plane(plane, polygon, scratch);
+        std::mem::swap(polygon, scratch);
+
+        if polygon.len() < 3 {
+            polygon.clear();
+            return;
+        }
+    }
+}
+
+/// Clips a line segment against every plane in `planes` (a mask of `ClipPlane::bit`) in place.
+/// Returns false if none of the segment is left.
+pub fn clip_line(a: &mut ClipVertex, b: &mut ClipVertex, planes: u32) -> bool {
+    let mut t0 = 0.0f32;
+    let mut t1 = 1.0f32;
+    for plane in ClipPlane::ALL {
+        if planes & plane.bit() == 0 {
+            continue;
+        }
+
+        let dist_a = plane.distance(a.position);
+        let dist_b = plane.distance(b.position);
+        if dist_a < 0.0 && dist_b < 0.0 {
+            return false;
+        }
+
+        if dist_a < 0.0 {
+            t0 = t0.max(dist_a / (dist_a - dist_b));
+        } else if dist_b < 0.0 {
+            t1 = t1.min(dist_a / (dist_a - dist_b));
+        }
+    }
+
+    if t0 > t1 {
+        return false;
+    }
+
+    let (start, end) = (a.clone(), b.clone());
+    if t0 > 0.0 {
+        *a = ClipVertex::lerp(&start, &end, t0);
+    }
+    if t1 < 1.0 {
+        *b = ClipVertex::lerp(&start, &end, t1);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clip space vertex carrying its own position as its first varyings, to check they get interpolated along.
+    fn vertex(x: f32, y: f32, z: f32, w: f32) -> ClipVertex {
+        let mut varyings = Varyings::ZERO;
+        varyings.set_vec4(0, Vec4::new(x, y, z, w));
+        ClipVertex::new(Vec4::new(x, y, z, w), varyings)
+    }
+
+    fn all_planes() -> u32 {
+        ClipPlane::ALL.iter().fold(0, |mask, plane| mask | plane.bit())
+    }
+
+    #[test]
+    fn polygons_are_cut_at_the_near_plane() {
+        // One corner behind the camera, the other two in front
+        let mut polygon = vec![vertex(0.0, 0.5, -1.0, 1.0), vertex(-0.5, -0.5, 0.5, 1.0), vertex(0.5, -0.5, 0.5, 1.0)];
+        let mut scratch = Vec::new();
+        clip_polygon(&mut polygon, &mut scratch, ClipPlane::Near.bit());
+
+        // The corner behind is replaced by the two points two thirds of the way towards the others
+        let near = |p: Vec4, x: f32| (p.x - x).abs() < 1e-6 && (p.y + 1.0 / 6.0).abs() < 1e-6 && p.z == 0.0 && p.w == 1.0;
+        assert!(polygon.len() == 4);
+        assert!(polygon.iter().any(|v| near(v.position, -1.0 / 3.0)));
+        assert!(polygon.iter().any(|v| near(v.position, 1.0 / 3.0)));
+        assert!(polygon.iter().all(|v| v.varyings.get_vec4(0) == v.position));
+    }
+
+    #[test]
+    fn clipped_polygons_stay_inside_every_plane() {
+        // Sticks out of every side of the frustum
+        let mut polygon = vec![vertex(-3.0, -2.0, -1.0, 1.0), vertex(3.0, -2.0, 2.0, 1.0), vertex(0.0, 3.0, 0.5, 1.0)];
+        let mut scratch = Vec::new();
+        clip_polygon(&mut polygon, &mut scratch, all_planes());
+
+        assert!(polygon.len() >= 3);
+        for v in &polygon {
+            assert!(ClipPlane::ALL.iter().all(|plane| plane.distance(v.position) >= -1e-6));
+        }
+
+        // Nothing is left of a polygon entirely behind the camera
+        let mut behind = vec![vertex(0.0, 0.0, -1.0, 1.0), vertex(1.0, 0.0, -2.0, 1.0), vertex(0.0, 1.0, -0.5, 1.0)];
+        clip_polygon(&mut behind, &mut scratch, all_planes());
+        assert!(behind.is_empty());
+
+        // And one entirely inside is left as it was
+        let mut inside = vec![vertex(0.0, 0.0, 0.5, 1.0), vertex(0.5, 0.0, 0.5, 1.0), vertex(0.0, 0.5, 0.5, 1.0)];
+        clip_polygon(&mut inside, &mut scratch, all_planes());
+        assert!(inside.iter().map(|v| v.position).eq([Vec4::new(0.0, 0.0, 0.5, 1.0), Vec4::new(0.5, 0.0, 0.5, 1.0), Vec4::new(0.0, 0.5, 0.5, 1.0)]));
+    }
+
+    #[test]
+    fn lines_are_cut_at_the_planes_they_cross() {
+        let (mut a, mut b) = (vertex(0.0, 0.0, -1.0, 1.0), vertex(0.0, 0.0, 1.0, 1.0));
+        assert!(clip_line(&mut a, &mut b, all_planes()));
+        assert!(a.position == Vec4::new(0.0, 0.0, 0.0, 1.0) && b.position == Vec4::new(0.0, 0.0, 1.0, 1.0));
+        assert!(a.varyings.get_vec4(0) == a.position);
+
+        // Cut at both ends
+        let (mut a, mut b) = (vertex(-3.0, 0.0, 0.5, 1.0), vertex(3.0, 0.0, 0.5, 1.0));
+        assert!(clip_line(&mut a, &mut b, all_planes()));
+        assert!(a.position == Vec4::new(-1.0, 0.0, 0.5, 1.0) && b.position == Vec4::new(1.0, 0.0, 0.5, 1.0));
+
+        // Both ends outside the same plane
+        let (mut a, mut b) = (vertex(2.0, 0.0, 0.5, 1.0), vertex(3.0, 1.0, 0.5, 1.0));
+        assert!(!clip_line(&mut a, &mut b, all_planes()));
+
+        // Outside different planes, passing by the corner of the frustum
+        let (mut a, mut b) = (vertex(-0.5, 3.0, 0.5, 1.0), vertex(3.0, -0.5, 0.5, 1.0));
+        assert!(!clip_line(&mut a, &mut b, all_planes()));
+    }
+}
@@ -0,0 +1,920 @@
+use crate::inflate::zlib_decompress;
+use crate::texture::Texture;
+
+/// Largest width or height accepted from a file, guards against allocating for corrupt headers.
+const MAX_DIMENSION: u32 = 1 << 14;
+
+/// Reads a PNG, TGA, BMP or PPM file into a texture.
+pub fn load_image_file(path: &str) -> Result<Texture, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    load_image(&data)
+}
+
+/// Decodes an image, telling the format apart by its signature. TGA has none, so it's assumed
+/// for anything else.
+pub fn load_image(data: &[u8]) -> Result<Texture, String> {
+    if data.starts_with(&PNG_SIGNATURE) {
+        decode_png(data)
+    }
+    else if data.starts_with(b"BM") {
+        decode_bmp(data)
+    }
+    else if data.starts_with(b"P3") || data.starts_with(b"P6") {
+        decode_ppm(data)
+    }
+    else {
+        decode_tga(data)
+    }
+}
+
+/// Bounds checked little and big endian reads, so truncated files produce an error.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data: data, pos: 0 }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(count).filter(|&end| end <= self.data.len());
+        let Some(end) = end else {
+            return Err("Unexpected end of image data".to_string());
+        };
+
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_le(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32_le(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u32_be(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+fn check_dimensions(width: u32, height: u32) -> Result<(), String> {
+    if width == 0 || height == 0 {
+        return Err("Image has no pixels".to_string());
+    }
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(format!("Image dimensions {}x{} are too large", width, height));
+    }
+
+    Ok(())
+}
+
+/// Scales a value with the given bit depth to [0, 255].
+#[inline]
+fn expand_bits(value: u32, bits: u32) -> u8 {
+    let max = (1u64 << bits) - 1;
+    ((value as u64 * 255 + max / 2) / max) as u8
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Starting position and spacing of each of the seven Adam7 passes, as (x, y, dx, dy).
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+/// Decodes a PNG of any colour type and bit depth, interlaced or not.
+pub fn decode_png(data: &[u8]) -> Result<Texture, String> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(8)? != PNG_SIGNATURE {
+        return Err("Not a PNG file".to_string());
+    }
+
+    let mut header = None;
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut transparent: Option<[u16; 3]> = None;
+    let mut compressed = Vec::new();
+
+    loop {
+        let len = reader.u32_be()? as usize;
+        let chunk = reader.bytes(len + 4)?;
+        let expected = reader.u32_be()?;
+        if crc32(chunk) != expected {
+            return Err("PNG chunk checksum mismatch".to_string());
+        }
+
+        let (kind, body) = chunk.split_at(4);
+        match kind {
+            b"IHDR" => {
+                if body.len() != 13 {
+                    return Err("Corrupt PNG header".to_string());
+                }
+                let width = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let height = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                check_dimensions(width, height)?;
+
+                let (depth, color_type) = (body[8], body[9]);
+                let valid = match color_type {
+                    0 => matches!(depth, 1 | 2 | 4 | 8 | 16),
+                    3 => matches!(depth, 1 | 2 | 4 | 8),
+                    2 | 4 | 6 => matches!(depth, 8 | 16),
+                    _ => false,
+                };
+                if !valid {
+                    return Err(format!("Invalid PNG colour type {} with bit depth {}", color_type, depth));
+                }
+                if body[10] != 0 || body[11] != 0 {
+                    return Err("Unknown PNG compression or filter method".to_string());
+                }
+                if body[12] > 1 {
+                    return Err("Unknown PNG interlace method".to_string());
+                }
+
+                header = Some((width, height, depth as u32, color_type, body[12] == 1));
+            }
+            b"PLTE" => {
+                if body.len() % 3 != 0 || body.len() > 256 * 3 {
+                    return Err("Corrupt PNG palette".to_string());
+                }
+                palette = body.chunks(3).map(|c| [c[0], c[1], c[2], 255]).collect();
+            }
+            b"tRNS" => {
+                match header {
+                    Some((_, _, _, 3, _)) => {
+                        for (entry, &alpha) in palette.iter_mut().zip(body) {
+                            entry[3] = alpha;
+                        }
+                    }
+                    Some((_, _, _, 0, _)) if body.len() >= 2 => {
+                        let gray = u16::from_be_bytes([body[0], body[1]]);
+                        transparent = Some([gray; 3]);
+                    }
+                    Some((_, _, _, 2, _)) if body.len() >= 6 => {
+                        let channel = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+                        transparent = Some([channel(0), channel(2), channel(4)]);
+                    }
+                    _ => {}
+                }
+            }
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {
+                // Bit 5 of the first byte marks ancillary chunks, which can be skipped
+                if kind[0] & 0x20 == 0 {
+                    return Err(format!("Unsupported critical PNG chunk {}", String::from_utf8_lossy(kind)));
+                }
+            }
+        }
+    }
+
+    let Some((width, height, depth, color_type, interlaced)) = header else {
+        return Err("PNG has no header".to_string());
+    };
+    if color_type == 3 && palette.is_empty() {
+        return Err("Paletted PNG has no palette".to_string());
+    }
+
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        _ => 4,
+    };
+    let bits_per_pixel = channels * depth;
+    let passes: &[(u32, u32, u32, u32)] = if interlaced { &ADAM7 } else { &[(0, 0, 1, 1)] };
+
+    // Width, height and scanline stride of each pass, passes with no pixels have no scanlines either
+    let sizes: Vec<(u32, u32, usize)> = passes.iter()
+        .map(|&(x0, y0, dx, dy)| {
+            if x0 >= width || y0 >= height {
+                return (0, 0, 0);
+            }
+            let pass_width = (width - x0).div_ceil(dx);
+            (pass_width, (height - y0).div_ceil(dy), (pass_width * bits_per_pixel).div_ceil(8) as usize)
+        })
+        .collect();
+    // Every scanline starts with its filter type byte
+    let expected: usize = sizes.iter().map(|&(_, pass_height, stride)| (stride + 1) * pass_height as usize).sum();
+    let filtered = zlib_decompress(&compressed, expected)?;
+
+    let mut texels = vec![[0u8; 4]; (width * height) as usize];
+    let mut at = 0;
+
+    for (&(x0, y0, dx, dy), &(pass_width, pass_height, stride)) in passes.iter().zip(&sizes) {
+        if pass_height == 0 {
+            continue;
+        }
+
+        let len = (stride + 1) * pass_height as usize;
+        let Some(pass) = filtered.get(at..at + len) else {
+            return Err("PNG image data is truncated".to_string());
+        };
+        at += len;
+
+        let rows = unfilter_png(pass, stride, bits_per_pixel.div_ceil(8) as usize)?;
+        for (py, row) in rows.chunks(stride).enumerate() {
+            for px in 0..pass_width {
+                let x = x0 + px * dx;
+                let y = y0 + py as u32 * dy;
+                texels[(y * width + x) as usize] = png_pixel(row, px, depth, color_type, &palette, transparent)?;
+            }
+        }
+    }
+
+    Ok(Texture::from_rgba8(width as i32, height as i32, texels))
+}
+
+/// Reverses the per scanline filters of a pass, returning the scanlines without their filter bytes.
+fn unfilter_png(pass: &[u8], stride: usize, bytes_per_pixel: usize) -> Result<Vec<u8>, String> {
+    let mut out = vec![0u8; pass.len() / (stride + 1) * stride];
+
+    for (y, line) in pass.chunks(stride + 1).enumerate() {
+        let (filter, line) = (line[0], &line[1..]);
+        let (before, current) = out.split_at_mut(y * stride);
+        let current = &mut current[..stride];
+        let above = if y == 0 { None } else { Some(&before[(y - 1) * stride..]) };
+
+        for i in 0..stride {
+            let a = if i >= bytes_per_pixel { current[i - bytes_per_pixel] } else { 0 };
+            let b = above.map_or(0, |above| above[i]);
+            let c = if i >= bytes_per_pixel { above.map_or(0, |above| above[i - bytes_per_pixel]) } else { 0 };
+
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(format!("Unknown PNG filter type {}", filter)),
+            };
+            current[i] = line[i].wrapping_add(predicted);
+        }
+    }
+
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+/// The pixel at `x` of an unfiltered scanline, expanded to 8 bit RGBA.
+fn png_pixel(row: &[u8], x: u32, depth: u32, color_type: u8, palette: &[[u8; 4]], transparent: Option<[u16; 3]>) -> Result<[u8; 4], String> {
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        _ => 4,
+    };
+
+    // Samples at full precision, used for the transparent colour key
+    let mut samples = [0u16; 4];
+    for (c, sample) in samples.iter_mut().enumerate().take(channels) {
+        let index = x as usize * channels + c;
+        *sample = match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth as usize;
+                let shift = 8 - depth as usize - bit % 8;
+                ((row[bit / 8] >> shift) & ((1 << depth) - 1) as u8) as u16
+            }
+        };
+    }
+
+    let to_u8 = |sample: u16| if depth == 16 { (sample >> 8) as u8 } else { expand_bits(sample as u32, depth) };
+    let opaque = |key: [u16; 3]| if transparent == Some(key) { 0 } else { 255 };
+
+    Ok(match color_type {
+        0 => {
+            let gray = to_u8(samples[0]);
+            [gray, gray, gray, opaque([samples[0]; 3])]
+        }
+        2 => [to_u8(samples[0]), to_u8(samples[1]), to_u8(samples[2]), opaque([samples[0], samples[1], samples[2]])],
+        3 => *palette.get(samples[0] as usize).ok_or("PNG palette index out of range")?,
+        4 => {
+            let gray = to_u8(samples[0]);
+            [gray, gray, gray, to_u8(samples[1])]
+        }
+        _ => [to_u8(samples[0]), to_u8(samples[1]), to_u8(samples[2]), to_u8(samples[3])],
+    })
+}
+
+/// Decodes an uncompressed or run length encoded TGA, colour mapped, true colour or grayscale.
+pub fn decode_tga(data: &[u8]) -> Result<Texture, String> {
+    let mut reader = Reader::new(data);
+    let id_len = reader.u8()?;
+    let has_color_map = reader.u8()?;
+    let image_type = reader.u8()?;
+    let map_first = reader.u16_le()?;
+    let map_len = reader.u16_le()?;
+    let map_depth = reader.u8()?;
+    let _origin = reader.bytes(4)?;
+    let width = reader.u16_le()? as u32;
+    let height = reader.u16_le()? as u32;
+    let depth = reader.u8()?;
+    let descriptor = reader.u8()?;
+
+    let rle = image_type & 8 != 0;
+    let kind = image_type & !8;
+    if !matches!(kind, 1..=3) || has_color_map > 1 {
+        return Err(format!("Unsupported TGA image type {}", image_type));
+    }
+    check_dimensions(width, height)?;
+
+    let valid_depth = match kind {
+        1 => depth == 8 && has_color_map == 1,
+        2 => matches!(depth, 15 | 16 | 24 | 32),
+        _ => depth == 8,
+    };
+    if !valid_depth {
+        return Err(format!("Unsupported TGA bit depth {} for image type {}", depth, image_type));
+    }
+
+    reader.bytes(id_len as usize)?;
+
+    let mut palette = Vec::new();
+    if has_color_map == 1 {
+        if !matches!(map_depth, 15 | 16 | 24 | 32) {
+            return Err(format!("Unsupported TGA colour map depth {}", map_depth));
+        }
+        let entry_size = (map_depth as usize).div_ceil(8);
+        for _ in 0..map_len {
+            palette.push(tga_color(reader.bytes(entry_size)?));
+        }
+    }
+
+    let pixel_size = (depth as usize).div_ceil(8);
+    let read_pixel = |bytes: &[u8]| -> Result<[u8; 4], String> {
+        match kind {
+            1 => {
+                let index = (bytes[0] as usize).checked_sub(map_first as usize);
+                index.and_then(|i| palette.get(i).copied()).ok_or("TGA colour map index out of range".to_string())
+            }
+            2 => Ok(tga_color(bytes)),
+            _ => Ok([bytes[0], bytes[0], bytes[0], 255]),
+        }
+    };
+
+    let count = (width * height) as usize;
+    let mut pixels = Vec::with_capacity(count);
+    if rle {
+        while pixels.len() < count {
+            let packet = reader.u8()?;
+            let run = (packet & 0x7f) as usize + 1;
+            if run > count - pixels.len() {
+                return Err("TGA run crosses the end of the image".to_string());
+            }
+
+            if packet & 0x80 != 0 {
+                let pixel = read_pixel(reader.bytes(pixel_size)?)?;
+                pixels.extend(std::iter::repeat_n(pixel, run));
+            }
+            else {
+                for _ in 0..run {
+                    pixels.push(read_pixel(reader.bytes(pixel_size)?)?);
+                }
+            }
+        }
+    }
+    else {
+        for _ in 0..count {
+            pixels.push(read_pixel(reader.bytes(pixel_size)?)?);
+        }
+    }
+
+    // Rows are stored bottom to top unless bit 5 is set, and right to left if bit 4 is
+    let top_down = descriptor & 0x20 != 0;
+    let right_to_left = descriptor & 0x10 != 0;
+    let mut texels = vec![[0u8; 4]; count];
+    for (i, pixel) in pixels.into_iter().enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let x = if right_to_left { width - 1 - x } else { x };
+        let y = if top_down { y } else { height - 1 - y };
+        texels[(y * width + x) as usize] = pixel;
+    }
+
+    Ok(Texture::from_rgba8(width as i32, height as i32, texels))
+}
+
+/// A little endian BGR(A) TGA colour of 2, 3 or 4 bytes.
+fn tga_color(bytes: &[u8]) -> [u8; 4] {
+    match bytes.len() {
+        2 => {
+            let v = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+            [expand_bits((v >> 10) & 31, 5), expand_bits((v >> 5) & 31, 5), expand_bits(v & 31, 5), 255]
+        }
+        3 => [bytes[2], bytes[1], bytes[0], 255],
+        _ => [bytes[2], bytes[1], bytes[0], bytes[3]],
+    }
+}
+
+/// Decodes an uncompressed BMP of 1, 4, 8, 16, 24 or 32 bits per pixel, including bit field masks.
+pub fn decode_bmp(data: &[u8]) -> Result<Texture, String> {
+    let mut reader = Reader::new(data);
+    if reader.bytes(2)? != b"BM" {
+        return Err("Not a BMP file".to_string());
+    }
+    reader.bytes(8)?;
+    let pixel_offset = reader.u32_le()? as usize;
+
+    let header_size = reader.u32_le()?;
+    let (width, height, depth, compression) = match header_size {
+        12 => {
+            let width = reader.u16_le()? as i32;
+            // Unlike the later headers, both sizes are unsigned so rows are always stored bottom up
+            let height = reader.u16_le()? as i32;
+            reader.u16_le()?;
+            (width, height, reader.u16_le()?, 0)
+        }
+        40.. => {
+            let width = reader.u32_le()? as i32;
+            let height = reader.u32_le()? as i32;
+            reader.u16_le()?;
+            let depth = reader.u16_le()?;
+            (width, height, depth, reader.u32_le()?)
+        }
+        _ => return Err(format!("Unsupported BMP header size {}", header_size)),
+    };
+
+    // A negative height means rows are stored top to bottom
+    let top_down = height < 0;
+    let (width, height) = (width.unsigned_abs(), height.unsigned_abs());
+    check_dimensions(width, height)?;
+
+    let mut masks = match depth {
+        16 => [0x7c00, 0x03e0, 0x001f, 0],
+        24 | 32 => [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0],
+        1 | 4 | 8 => [0; 4],
+        _ => return Err(format!("Unsupported BMP bit depth {}", depth)),
+    };
+
+    let mut palette_len = 0;
+    if header_size >= 40 {
+        // Skips image size and resolution
+        reader.bytes(12)?;
+        palette_len = reader.u32_le()? as usize;
+        reader.u32_le()?;
+
+        match compression {
+            0 => {}
+            // BI_BITFIELDS and BI_ALPHABITFIELDS, the masks follow the 40 byte header when it isn't larger
+            3 | 6 if depth == 16 || depth == 32 => {
+                let mask_count = if compression == 6 || header_size >= 56 { 4 } else { 3 };
+                for mask in masks.iter_mut().take(mask_count) {
+                    *mask = reader.u32_le()?;
+                }
+            }
+            _ => return Err(format!("Unsupported BMP compression {}", compression)),
+        }
+    }
+
+    let mut palette = Vec::new();
+    if depth <= 8 {
+        if palette_len == 0 || palette_len > 1 << depth {
+            palette_len = 1 << depth;
+        }
+
+        // Skip to the colour table, the masks above may be part of a larger header
+        reader.pos = 14 + header_size as usize;
+        let entry_size = if header_size == 12 { 3 } else { 4 };
+        for _ in 0..palette_len {
+            let entry = reader.bytes(entry_size)?;
+            palette.push([entry[2], entry[1], entry[0], 255]);
+        }
+    }
+
+    let stride = (width as usize * depth as usize).div_ceil(32) * 4;
+    reader.pos = pixel_offset;
+    let pixels = reader.bytes(stride * height as usize)?;
+
+    let channel = |value: u32, mask: u32| -> Option<u8> {
+        if mask == 0 {
+            return None;
+        }
+        let shift = mask.trailing_zeros();
+        Some(expand_bits((value & mask) >> shift, (mask >> shift).trailing_ones()))
+    };
+
+    let mut texels = vec![[0u8; 4]; (width * height) as usize];
+    for (row_index, row) in pixels.chunks(stride).enumerate() {
+        let y = if top_down { row_index } else { height as usize - 1 - row_index };
+
+        for x in 0..width as usize {
+            let texel = match depth {
+                1 | 4 | 8 => {
+                    let bit = x * depth as usize;
+                    let shift = 8 - depth as usize - bit % 8;
+                    let index = (row[bit / 8] >> shift) & ((1u16 << depth) - 1) as u8;
+                    *palette.get(index as usize).ok_or("BMP palette index out of range")?
+                }
+                _ => {
+                    let size = depth as usize / 8;
+                    let mut bytes = [0u8; 4];
+                    bytes[..size].copy_from_slice(&row[x * size..(x + 1) * size]);
+                    let value = u32::from_le_bytes(bytes);
+
+                    [
+                        channel(value, masks[0]).unwrap_or(0),
+                        channel(value, masks[1]).unwrap_or(0),
+                        channel(value, masks[2]).unwrap_or(0),
+                        channel(value, masks[3]).unwrap_or(255),
+                    ]
+                }
+            };
+            texels[y * width as usize + x] = texel;
+        }
+    }
+
+    Ok(Texture::from_rgba8(width as i32, height as i32, texels))
+}
+
+/// Decodes an ASCII (P3) or binary (P6) PPM with any maximum value up to 65535.
+pub fn decode_ppm(data: &[u8]) -> Result<Texture, String> {
+    let mut reader = Reader::new(data);
+    let binary = match reader.bytes(2)? {
+        b"P3" => false,
+        b"P6" => true,
+        _ => return Err("Not a PPM file".to_string()),
+    };
+
+    let width = ppm_number(&mut reader)?;
+    let height = ppm_number(&mut reader)?;
+    let max = ppm_number(&mut reader)?;
+    check_dimensions(width, height)?;
+    if max == 0 || max > 65535 {
+        return Err(format!("Invalid PPM maximum value {}", max));
+    }
+
+    let count = (width * height) as usize;
+    let mut texels = Vec::with_capacity(count);
+    let scale = |value: u32| -> Result<u8, String> {
+        if value > max {
+            return Err("PPM sample exceeds the maximum value".to_string());
+        }
+        Ok(((value * 255 + max / 2) / max) as u8)
+    };
+
+    if binary {
+        // A single whitespace byte separates the header from the samples
+        reader.u8()?;
+        let sample_size = if max < 256 { 1 } else { 2 };
+        let samples = reader.bytes(count * 3 * sample_size)?;
+
+        for pixel in samples.chunks(3 * sample_size) {
+            let sample = |i: usize| -> u32 {
+                if sample_size == 1 { pixel[i] as u32 } else { u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]) as u32 }
+            };
+            texels.push([scale(sample(0))?, scale(sample(1))?, scale(sample(2))?, 255]);
+        }
+    }
+    else {
+        for _ in 0..count {
+            let r = scale(ppm_number(&mut reader)?)?;
+            let g = scale(ppm_number(&mut reader)?)?;
+            let b = scale(ppm_number(&mut reader)?)?;
+            texels.push([r, g, b, 255]);
+        }
+    }
+
+    Ok(Texture::from_rgba8(width as i32, height as i32, texels))
+}
+
+/// The next decimal number of a PPM, skipping whitespace and comments before it.
+fn ppm_number(reader: &mut Reader) -> Result<u32, String> {
+    loop {
+        match reader.data.get(reader.pos) {
+            Some(b'#') => {
+                while reader.data.get(reader.pos).is_some_and(|&c| c != b'\n') {
+                    reader.pos += 1;
+                }
+            }
+            Some(c) if c.is_ascii_whitespace() => reader.pos += 1,
+            Some(_) => break,
+            None => return Err("Unexpected end of PPM data".to_string()),
+        }
+    }
+
+    let start = reader.pos;
+    while reader.data.get(reader.pos).is_some_and(|c| c.is_ascii_digit()) {
+        reader.pos += 1;
+    }
+
+    std::str::from_utf8(&reader.data[start..reader.pos])
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or("Invalid number in PPM header".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::TextureData;
+
+    fn texels(texture: &Texture) -> Vec<[u8; 4]> {
+        match &texture.levels[0].data {
+            TextureData::Rgba8(texels) => texels.to_vec(),
+            TextureData::Rgba32F(_) => panic!("Expected an 8 bit texture"),
+        }
+    }
+
+    /// A pattern with no two neighbours alike, so every filter has something to predict.
+    fn pattern(width: u32, height: u32) -> Vec<[u8; 4]> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 37 + y * 11) as u8, (x * y * 5 + 3) as u8, (255 - x * 9 + y * y) as u8, (128 + x * 3 - y * 7) as u8]
+            })
+            .collect()
+    }
+
+    /// Wraps data in a zlib stream of stored blocks.
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x78, 0x01];
+        let blocks: Vec<&[u8]> = data.chunks(0xffff).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            out.push((i + 1 == blocks.len()) as u8);
+            out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        out
+    }
+
+    fn png_chunk(out: &mut Vec<u8>, kind: &[u8], body: &[u8]) {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(body);
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&chunk);
+        out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+    }
+
+    /// Encodes an 8 bit RGBA PNG, cycling through the five filter types row by row.
+    fn encode_png(width: u32, height: u32, pixels: &[[u8; 4]], interlaced: bool) -> Vec<u8> {
+        let passes: &[(u32, u32, u32, u32)] = if interlaced { &ADAM7 } else { &[(0, 0, 1, 1)] };
+        let mut filtered = Vec::new();
+        let mut filter = 0;
+
+        // Passes without any pixels are left out entirely
+        for &(x0, y0, dx, dy) in passes.iter().filter(|&&(x0, y0, _, _)| x0 < width && y0 < height) {
+            let rows: Vec<Vec<u8>> = (y0..height).step_by(dy as usize)
+                .map(|y| (x0..width).step_by(dx as usize).flat_map(|x| pixels[(y * width + x) as usize]).collect())
+                .collect();
+
+            for (y, row) in rows.iter().enumerate() {
+                filtered.push(filter);
+                for i in 0..row.len() {
+                    let a = if i >= 4 { row[i - 4] } else { 0 };
+                    let b = if y > 0 { rows[y - 1][i] } else { 0 };
+                    let c = if i >= 4 && y > 0 { rows[y - 1][i - 4] } else { 0 };
+                    let predicted = match filter {
+                        0 => 0,
+                        1 => a,
+                        2 => b,
+                        3 => ((a as u16 + b as u16) / 2) as u8,
+                        _ => paeth(a, b, c),
+                    };
+                    filtered.push(row[i].wrapping_sub(predicted));
+                }
+                filter = (filter + 1) % 5;
+            }
+        }
+
+        let mut header = width.to_be_bytes().to_vec();
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[8, 6, 0, 0, interlaced as u8]);
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        png_chunk(&mut out, b"IHDR", &header);
+        png_chunk(&mut out, b"IDAT", &zlib_stored(&filtered));
+        png_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    fn decodes_every_png_filter() {
+        let pixels = pattern(13, 11);
+        let png = encode_png(13, 11, &pixels, false);
+        assert_eq!(texels(&load_image(&png).unwrap()), pixels);
+
+        let mut corrupt = png.clone();
+        corrupt[40] ^= 1;
+        assert!(load_image(&corrupt).is_err());
+    }
+
+    #[test]
+    fn stops_inflating_png_data_past_the_last_scanline() {
+        let png = |filtered: &[u8]| {
+            let mut out = PNG_SIGNATURE.to_vec();
+            png_chunk(&mut out, b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+            png_chunk(&mut out, b"IDAT", &zlib_stored(filtered));
+            png_chunk(&mut out, b"IEND", &[]);
+            out
+        };
+
+        // A filter byte and one RGBA pixel, then a megabyte the image has no room for
+        assert_eq!(texels(&load_image(&png(&[0, 1, 2, 3, 4])).unwrap()), [[1, 2, 3, 4]]);
+        let mut oversized = vec![0, 1, 2, 3, 4];
+        oversized.resize(1 << 20, 0);
+        assert_eq!(load_image(&png(&oversized)).err().unwrap(), "Deflate stream decompresses to more than 5 bytes");
+    }
+
+    #[test]
+    fn decodes_interlaced_pngs() {
+        // Sizes that leave some of the Adam7 passes empty or partly filled
+        for (width, height) in [(13, 11), (3, 2), (1, 1)] {
+            let pixels = pattern(width, height);
+            let texture = load_image(&encode_png(width, height, &pixels, true)).unwrap();
+            assert_eq!(texels(&texture), pixels);
+        }
+    }
+
+    #[test]
+    fn decodes_run_length_encoded_tgas() {
+        // 4x2 BGR, stored bottom row first
+        let mut tga = vec![0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 2, 0, 24, 0];
+        tga.extend_from_slice(&[0x82, 0, 0, 255]);
+        tga.extend_from_slice(&[0x01, 0, 255, 0, 255, 0, 0]);
+        tga.extend_from_slice(&[0x82, 255, 255, 255]);
+
+        let red = [255, 0, 0, 255];
+        let green = [0, 255, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let white = [255; 4];
+        assert_eq!(texels(&load_image(&tga).unwrap()), [blue, white, white, white, red, red, red, green]);
+
+        // A run past the last pixel
+        tga[22] = 0x83;
+        assert!(load_image(&tga).is_err());
+    }
+
+    #[test]
+    fn decodes_uncompressed_tgas() {
+        // 2x2 BGRA stored top down and right to left, after a three byte image ID
+        let mut tga = vec![3, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 32, 0x38];
+        tga.extend_from_slice(b"abc");
+        tga.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        assert_eq!(texels(&load_image(&tga).unwrap()), [[7, 6, 5, 8], [3, 2, 1, 4], [15, 14, 13, 16], [11, 10, 9, 12]]);
+
+        tga.pop();
+        assert!(load_image(&tga).is_err());
+    }
+
+    #[test]
+    fn decodes_color_mapped_tgas() {
+        // 3x1, with a two entry BGR colour map starting at index 1
+        let mut tga = vec![0, 1, 1, 1, 0, 2, 0, 24, 0, 0, 0, 0, 3, 0, 1, 0, 8, 0];
+        tga.extend_from_slice(&[255, 0, 0, 0, 0, 255]);
+        tga.extend_from_slice(&[2, 1, 2]);
+
+        let blue = [0, 0, 255, 255];
+        let red = [255, 0, 0, 255];
+        assert_eq!(texels(&load_image(&tga).unwrap()), [red, blue, red]);
+
+        // Index 0 comes before the first entry of the map
+        tga[25] = 0;
+        assert!(load_image(&tga).is_err());
+    }
+
+    #[test]
+    fn decodes_ascii_and_binary_ppms() {
+        let ascii = b"P3\n# Two pixels\n2 1\n100 # maximum\n0 50 100  100 100 0\n";
+        assert_eq!(texels(&load_image(ascii).unwrap()), [[0, 128, 255, 255], [255, 255, 0, 255]]);
+        assert!(load_image(b"P3 1 1 100 0 101 0").is_err());
+
+        let mut binary = b"P6 2 1 255\n".to_vec();
+        binary.extend_from_slice(&[10, 20, 30, 40, 50, 60]);
+        assert_eq!(texels(&load_image(&binary).unwrap()), [[10, 20, 30, 255], [40, 50, 60, 255]]);
+        binary.pop();
+        assert!(load_image(&binary).is_err());
+
+        // Two bytes per sample, most significant first
+        let mut wide = b"P6\n1 1\n65535\n".to_vec();
+        wide.extend_from_slice(&[0xff, 0xff, 0x80, 0x00, 0x00, 0xff]);
+        assert_eq!(texels(&load_image(&wide).unwrap()), [[255, 128, 1, 255]]);
+    }
+
+    /// A bottom up BMP with bit field masks written right after the first 40 bytes of the header, or a palette
+    /// after the whole header if there are no masks.
+    fn encode_bmp(width: u32, height: u32, depth: u16, header_size: u32, masks: &[u32], palette: &[[u8; 4]], rows: &[u8]) -> Vec<u8> {
+        let pixel_offset = 14 + header_size.max(40 + masks.len() as u32 * 4) + palette.len() as u32 * 4;
+
+        let mut out = b"BM".to_vec();
+        out.extend_from_slice(&(pixel_offset + rows.len() as u32).to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&pixel_offset.to_le_bytes());
+
+        out.extend_from_slice(&header_size.to_le_bytes());
+        out.extend_from_slice(&width.to_le_bytes());
+        out.extend_from_slice(&height.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&depth.to_le_bytes());
+        out.extend_from_slice(&(if masks.is_empty() { 0u32 } else { 3 }).to_le_bytes());
+        out.extend_from_slice(&[0; 12]);
+        out.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        for mask in masks {
+            out.extend_from_slice(&mask.to_le_bytes());
+        }
+
+        out.resize(pixel_offset as usize - palette.len() * 4, 0);
+        for &[r, g, b, _] in palette {
+            out.extend_from_slice(&[b, g, r, 0]);
+        }
+        out.extend_from_slice(rows);
+        out
+    }
+
+    #[test]
+    fn decodes_bmp_bitfields() {
+        // 2x2 RGB565, each row padded to four bytes
+        let rows = [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0xff, 0xff];
+        let bmp = encode_bmp(2, 2, 16, 40, &[0xf800, 0x07e0, 0x001f], &[], &rows);
+        assert_eq!(texels(&load_image(&bmp).unwrap()), [[0, 0, 255, 255], [255; 4], [255, 0, 0, 255], [0, 255, 0, 255]]);
+
+        // 2x1 with red in the low byte and alpha in a V4 header
+        let rows = [0x10, 0x20, 0x30, 0x40, 0xff, 0x00, 0x80, 0x00];
+        let bmp = encode_bmp(2, 1, 32, 108, &[0x0000_00ff, 0x0000_ff00, 0x00ff_0000, 0xff00_0000], &[], &rows);
+        assert_eq!(texels(&load_image(&bmp).unwrap()), [[0x10, 0x20, 0x30, 0x40], [0xff, 0x00, 0x80, 0x00]]);
+    }
+
+    #[test]
+    fn decodes_bmp_palettes() {
+        let black = [0, 0, 0, 255];
+        let gray = [128, 128, 128, 255];
+        let orange = [255, 128, 0, 255];
+
+        // 3x2 at 8 bits per pixel, with a palette of only as many colours as used
+        let rows = [2, 1, 0, 0, 0, 1, 2, 0];
+        let bmp = encode_bmp(3, 2, 8, 40, &[], &[black, gray, orange], &rows);
+        assert_eq!(texels(&load_image(&bmp).unwrap()), [black, gray, orange, orange, gray, black]);
+
+        // Index 3 is past the end of the palette
+        let bmp = encode_bmp(3, 2, 8, 40, &[], &[black, gray, orange], &[3, 1, 0, 0, 0, 1, 2, 0]);
+        assert!(load_image(&bmp).is_err());
+
+        // 3x1 at 4 bits per pixel, the first pixel in the high nibble
+        let bmp = encode_bmp(3, 1, 4, 40, &[], &[black, gray, orange], &[0x21, 0x00, 0, 0]);
+        assert_eq!(texels(&load_image(&bmp).unwrap()), [orange, gray, black]);
+    }
+
+    #[test]
+    fn decodes_bmp_core_headers() {
+        // 2x2 at 1 bit per pixel with a three byte per entry palette of blue and red, each row padded to four bytes
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[40, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0]);
+        bmp.extend_from_slice(&[12, 0, 0, 0, 2, 0, 2, 0, 1, 0, 1, 0]);
+        bmp.extend_from_slice(&[255, 0, 0, 0, 0, 255]);
+        bmp.extend_from_slice(&[0x80, 0, 0, 0, 0x40, 0, 0, 0]);
+
+        let blue = [0, 0, 255, 255];
+        let red = [255, 0, 0, 255];
+        assert_eq!(texels(&load_image(&bmp).unwrap()), [blue, red, red, blue]);
+
+        // Heights from 32768 up aren't negative
+        bmp[20] = 0xfe;
+        bmp[21] = 0xff;
+        assert_eq!(load_image(&bmp).err().unwrap(), "Image dimensions 2x65534 are too large");
+    }
+}
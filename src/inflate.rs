@@ -0,0 +1,403 @@
+/// Reads a DEFLATE stream least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data: data, pos: 0, bit_buf: 0, bit_count: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        while self.bit_count < count {
+            let Some(&byte) = self.data.get(self.pos) else {
+                return Err("Unexpected end of deflate stream".to_string());
+            };
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+
+        let value = self.bit_buf & ((1u64 << count) - 1) as u32;
+        self.bit_buf >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte.
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+const MAX_BITS: usize = 15;
+
+/// A canonical Huffman code, stored as the number of codes of each length and the symbols
+/// ordered by code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, String> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Over-subscribed codes can't be decoded, incomplete ones are allowed
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err("Over-subscribed Huffman code".to_string());
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts: counts, symbols: symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err("Invalid Huffman code".to_string())
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Order the code length code lengths are stored in by dynamic blocks.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decompresses a raw DEFLATE stream (RFC 1951), failing as soon as it produces more than `max_output` bytes.
+pub fn inflate(data: &[u8], max_output: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader::new(data);
+    let mut out = Vec::with_capacity((data.len() * 4).min(max_output));
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => stored_block(&mut reader, &mut out, max_output)?,
+            1 => {
+                let (lit_len, dist) = fixed_codes()?;
+                compressed_block(&mut reader, &mut out, max_output, &lit_len, &dist)?;
+            }
+            2 => {
+                let (lit_len, dist) = dynamic_codes(&mut reader)?;
+                compressed_block(&mut reader, &mut out, max_output, &lit_len, &dist)?;
+            }
+            _ => return Err("Invalid deflate block type".to_string()),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Decompresses a zlib stream (RFC 1950), checking its header and Adler-32 checksum. Fails like `inflate`
+/// if it decompresses to more than `max_output` bytes.
+pub fn zlib_decompress(data: &[u8], max_output: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 6 {
+        return Err("zlib stream too short".to_string());
+    }
+
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || (cmf >> 4) > 7 {
+        return Err("zlib stream isn't deflate compressed".to_string());
+    }
+    if !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+        return Err("Corrupt zlib header".to_string());
+    }
+    if flg & 0x20 != 0 {
+        return Err("zlib preset dictionaries aren't supported".to_string());
+    }
+
+    let out = inflate(&data[2..], max_output)?;
+
+    let tail = &data[data.len() - 4..];
+    let expected = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]);
+    if adler32(&out) != expected {
+        return Err("zlib checksum mismatch".to_string());
+    }
+
+    Ok(out)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the most bytes that can be summed before b can overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+fn too_long(max_output: usize) -> String {
+    format!("Deflate stream decompresses to more than {} bytes", max_output)
+}
+
+fn stored_block(reader: &mut BitReader, out: &mut Vec<u8>, max_output: usize) -> Result<(), String> {
+    reader.align();
+
+    let header = reader.data.get(reader.pos..reader.pos + 4).ok_or("Unexpected end of deflate stream")?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let nlen = u16::from_le_bytes([header[2], header[3]]);
+    if len != !nlen {
+        return Err("Corrupt stored block length".to_string());
+    }
+    reader.pos += 4;
+
+    let bytes = reader.data.get(reader.pos..reader.pos + len as usize).ok_or("Unexpected end of deflate stream")?;
+    if out.len() + bytes.len() > max_output {
+        return Err(too_long(max_output));
+    }
+    out.extend_from_slice(bytes);
+    reader.pos += len as usize;
+
+    Ok(())
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman), String> {
+    let mut lengths = [0u8; 288];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5u8; 30])?))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let lit_len_count = reader.bits(5)? as usize + 257;
+    let dist_count = reader.bits(5)? as usize + 1;
+    let code_len_count = reader.bits(4)? as usize + 4;
+    if lit_len_count > 286 || dist_count > 30 {
+        return Err("Too many codes in dynamic block".to_string());
+    }
+
+    let mut code_len_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_len_count] {
+        code_len_lengths[i] = reader.bits(3)? as u8;
+    }
+    let code_len = Huffman::new(&code_len_lengths)?;
+
+    // Literal/length and distance code lengths are run length encoded as a single sequence
+    let mut lengths = vec![0u8; lit_len_count + dist_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_len.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err("Repeated code length with no previous length".to_string());
+                }
+                (lengths[i - 1], 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+
+        if i + repeat > lengths.len() {
+            return Err("Code lengths overflow dynamic block".to_string());
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+
+    if lengths[256] == 0 {
+        return Err("Dynamic block has no end of block code".to_string());
+    }
+
+    Ok((Huffman::new(&lengths[..lit_len_count])?, Huffman::new(&lengths[lit_len_count..])?))
+}
+
+fn compressed_block(reader: &mut BitReader, out: &mut Vec<u8>, max_output: usize, lit_len: &Huffman, dist: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = lit_len.decode(reader)? as usize;
+        match symbol {
+            0..=255 => {
+                if out.len() == max_output {
+                    return Err(too_long(max_output));
+                }
+                out.push(symbol as u8);
+            }
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err("Invalid length code".to_string());
+                }
+                let len = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+                let index = dist.decode(reader)? as usize;
+                if index >= DIST_BASE.len() {
+                    return Err("Invalid distance code".to_string());
+                }
+                let distance = DIST_BASE[index] as usize + reader.bits(DIST_EXTRA[index] as u32)? as usize;
+                if distance > out.len() {
+                    return Err("Distance reaches before start of output".to_string());
+                }
+                if out.len() + len > max_output {
+                    return Err(too_long(max_output));
+                }
+
+                // The copy may overlap the bytes it produces
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORED: [u8; 17] = [0x01, 0x0c, 0x00, 0xf3, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c, 0x6f, 0x63, 0x6b];
+    const FIXED: [u8; 13] = [0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x0a, 0x69, 0x99, 0x15, 0xa9, 0x29, 0x00];
+
+    #[test]
+    fn inflates_stored_blocks() {
+        assert_eq!(inflate(&STORED, usize::MAX).unwrap(), b"stored block");
+
+        let mut corrupt = STORED;
+        corrupt[3] ^= 1;
+        assert!(inflate(&corrupt, usize::MAX).is_err());
+        assert!(inflate(&STORED[..10], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn inflates_fixed_blocks() {
+        // Includes an overlapping copy of "abc"
+        assert_eq!(inflate(&FIXED, usize::MAX).unwrap(), b"abcabcabcabcabc fixed");
+    }
+
+    #[test]
+    fn inflates_dynamic_blocks() {
+        let compressed = [
+            0x85, 0xd0, 0xbb, 0x0d, 0x80, 0x30, 0x0c, 0x04, 0xd0, 0x55, 0x6e, 0x00, 0x8a, 0x98, 0x3f, 0xe3,
+            0x10, 0xc9, 0x11, 0x85, 0x85, 0x25, 0x88, 0xc4, 0xfa, 0x6c, 0x70, 0x57, 0xbf, 0xee, 0x15, 0xd4,
+            0xec, 0x3d, 0xfc, 0x45, 0x36, 0x54, 0xf7, 0x07, 0x79, 0xa3, 0x5f, 0x8e, 0xef, 0x8c, 0x18, 0x60,
+            0x9c, 0x47, 0xce, 0x13, 0xe7, 0x99, 0xf3, 0xc2, 0x79, 0xe5, 0xbc, 0x71, 0xde, 0x39, 0x1f, 0xa2,
+            0xa5, 0x08, 0x17, 0x6f, 0x26, 0xe2, 0x4c, 0xcc, 0x99, 0xa8, 0xfb, 0x01,
+        ];
+        let expected: String = (0..15).map(|i| format!("{} bottles of beer on the wall, ", i)).collect();
+
+        assert_eq!(compressed[0] >> 1 & 3, 2);
+        assert_eq!(inflate(&compressed, usize::MAX).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn inflates_several_blocks() {
+        // The stored block with its final bit cleared, followed by the fixed one
+        let mut data = STORED.to_vec();
+        data[0] = 0;
+        data.extend_from_slice(&FIXED);
+
+        assert_eq!(inflate(&data, usize::MAX).unwrap(), b"stored blockabcabcabcabcabc fixed");
+    }
+
+    #[test]
+    fn checks_zlib_streams() {
+        let mut data = [0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x22, 0xab, 0x72, 0x32, 0x93, 0x00, 0x5f, 0x9f, 0x08, 0x4e];
+        assert_eq!(zlib_decompress(&data, usize::MAX).unwrap(), b"hello hello hello zlib");
+
+        data[19] ^= 1;
+        assert!(zlib_decompress(&data, usize::MAX).is_err());
+        data[19] ^= 1;
+        data[1] ^= 1;
+        assert!(zlib_decompress(&data, usize::MAX).is_err());
+    }
+
+    /// A fixed Huffman block of one literal followed by `copies` copies of the 258 bytes before them.
+    fn deflate_bomb(copies: usize) -> Vec<u8> {
+        // Final block of type 1, then Huffman codes which are stored most significant bit first
+        let mut bits = vec![1, 1, 0];
+        let mut code = |value: u32, len: u32| bits.extend((0..len).rev().map(|i| value >> i & 1));
+        code(0x30 + b'a' as u32, 8);
+        for _ in 0..copies {
+            // Length 258 is symbol 285, followed by distance code 0 for a distance of 1
+            code(0xc0 + 285 - 280, 8);
+            code(0, 5);
+        }
+        code(0, 7);
+
+        bits.chunks(8).map(|byte| byte.iter().rev().fold(0, |acc, &bit| acc << 1 | bit as u8)).collect()
+    }
+
+    #[test]
+    fn stops_at_the_output_limit() {
+        let bomb = deflate_bomb(4000);
+        let size = 1 + 258 * 4000;
+        assert!(bomb.len() * 100 < size);
+        assert!(inflate(&bomb, usize::MAX).unwrap().iter().all(|&b| b == b'a'));
+        assert_eq!(inflate(&bomb, size).unwrap().len(), size);
+        assert_eq!(inflate(&bomb, size - 1), Err(format!("Deflate stream decompresses to more than {} bytes", size - 1)));
+
+        // Literals and stored blocks are capped too
+        assert!(inflate(&FIXED, 21).is_ok() && inflate(&FIXED, 2).is_err());
+        assert!(inflate(&STORED, 12).is_ok() && inflate(&STORED, 11).is_err());
+
+        // The limit is hit long before the missing checksum is noticed
+        let mut zlib = vec![0x78, 0x9c];
+        zlib.extend_from_slice(&bomb);
+        zlib.extend_from_slice(&[0; 4]);
+        assert!(zlib_decompress(&zlib, 1 << 16).unwrap_err().contains("more than"));
+    }
+}
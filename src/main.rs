@@ -4,10 +4,24 @@ mod vmath;
 mod model;
 mod render_target;
 mod transform;
+mod clip;
+mod raster;
+mod tiles;
+mod workers;
+mod simd;
+mod shader;
+mod pipeline;
+mod texture;
+mod inflate;
+mod image;
 
 use raylib::prelude::*;
 use raylib::color;
 use render::Renderer;
+use render_target::SampleCount;
+use shader::{Uniforms, Instance, BasicVertexShader, NormalFragmentShader, TextureFragmentShader, DotFragmentShader};
+use texture::{Texture, Sampler};
+use pipeline::{PipelineState, PolygonMode, PrimitiveTopology, ScissorRect, BlendState, DepthBias, LineMode};
 use vmath::*;
 use transform::{ModelTransform};
 use model::Model;
@@ -16,6 +30,47 @@ use std::f32::consts::{PI};
 use self::transform::CameraTransform;
 use self::transform::WorldToScreenTransform;
 
+/// A wooden crate side in every 64x64 cell of a 256x256 texture, lined up with the faces of cube.obj.
+fn generate_crate_texture() -> Texture {
+    const CELL: i32 = 64;
+    const BORDER: i32 = 7;
+    const SEAM: [f32; 3] = [70.0, 44.0, 22.0];
+    const FRAME: [f32; 3] = [132.0, 88.0, 46.0];
+    const PLANK: [f32; 3] = [176.0, 124.0, 70.0];
+
+    let texel = |x: i32, y: i32| -> [u8; 4] {
+        let edge = x.min(y).min(CELL - 1 - x).min(CELL - 1 - y);
+        // Distance from the brace running from the bottom left to the top right, rows grow downwards
+        let brace = (x + y - (CELL - 1)).abs();
+        let plank = (y - BORDER) / 10;
+        if edge == 0 || brace == 5 || (edge >= BORDER && brace > 5 && (y - BORDER) % 10 == 9) {
+            return [SEAM[0] as u8, SEAM[1] as u8, SEAM[2] as u8, 255];
+        }
+
+        // Wood grain runs along the planks, and along whichever side of the frame the texel is on
+        let (color, along, across, shade) = if edge < BORDER {
+            let shade = if edge == BORDER - 1 { 0.75 } else { 1.0 };
+            if x.min(CELL - 1 - x) < y.min(CELL - 1 - y) { (FRAME, y, x, shade) } else { (FRAME, x, y, shade) }
+        }
+        else if brace < 5 {
+            (FRAME, x, y, 1.0)
+        }
+        else {
+            (PLANK, x + plank * 17, y, [1.0, 0.93, 1.04, 0.96, 1.0][plank as usize % 5])
+        };
+        let grain = (across as f32 * 0.9 + (along as f32 * 0.07 + across as f32 * 0.3).sin() * 2.0).sin() * 0.5 + 0.5;
+        let scale = (0.86 + 0.14 * grain) * shade;
+
+        [(color[0] * scale).min(255.0) as u8, (color[1] * scale).min(255.0) as u8, (color[2] * scale).min(255.0) as u8, 255]
+    };
+
+    // The faces of cube.obj start half a cell in from the left edge of the texture
+    let texels = (0..CELL * CELL * 16)
+        .map(|i| texel((i % (CELL * 4) - CELL / 2).rem_euclid(CELL), i / (CELL * 4) % CELL))
+        .collect();
+    Texture::from_rgba8(CELL * 4, CELL * 4, texels)
+}
+
 
 fn main() {
     const WIDTH: i32 = 1280;
@@ -25,7 +80,7 @@ fn main() {
     rl.set_trace_log(TraceLogLevel::LOG_WARNING);
 
     let mut backbuffer_texture = rl.load_render_texture(&thread, WIDTH as u32, HEIGHT as u32).unwrap();
-    let mut renderer = Renderer::new(WIDTH, HEIGHT);
+    let mut renderer = Renderer::new_multisampled(WIDTH, HEIGHT, SampleCount::X4);
 
     let cube_file = include_str!("../cube.obj");
     let cube = Model::load_from_data(cube_file).unwrap();
@@ -35,9 +90,69 @@ fn main() {
     
     let mut camera_pos = Vec3::ZERO;
 
+    // An image file given on the command line replaces the generated crate texture
+    let mut crate_texture = match std::env::args().nth(1) {
+        Some(path) => image::load_image_file(&path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            generate_crate_texture()
+        }),
+        None => generate_crate_texture(),
+    };
+    crate_texture.generate_mips();
+    let crate_shader = TextureFragmentShader { texture: &crate_texture, sampler: Sampler::TRILINEAR };
+    let mut pipeline = PipelineState::new(BasicVertexShader, crate_shader);
+    let mut instance_pipeline = PipelineState::new(BasicVertexShader, NormalFragmentShader);
+    // The cube's vertices as round dots, drawn instead of it in point mode
+    let mut dot_pipeline = PipelineState::new(BasicVertexShader, DotFragmentShader { color: Vec4::new(1.0, 0.8, 0.3, 1.0) });
+    dot_pipeline.polygon_mode = PolygonMode::Point;
+    dot_pipeline.point_size = 12.0;
+
+    // A floor below the cube, two triangles as a strip without an index buffer
+    let up = Vec3::new(0.0, 0.0, 1.0);
+    let floor = [
+        Vertex::new(Vec3::new(-20.0, -20.0, -1.5), up, Vec2::new(-10.0, -10.0)),
+        Vertex::new(Vec3::new(20.0, -20.0, -1.5), up, Vec2::new(10.0, -10.0)),
+        Vertex::new(Vec3::new(-20.0, 40.0, -1.5), up, Vec2::new(-10.0, 20.0)),
+        Vertex::new(Vec3::new(20.0, 40.0, -1.5), up, Vec2::new(10.0, 20.0)),
+    ];
+    // Two by two light and dark grey squares, repeated once per unit of the floor's texture coordinates
+    let checker = (0..64 * 64)
+        .map(|i| if (i % 64 < 32) == (i / 64 < 32) { [200, 200, 200, 255] } else { [90, 90, 90, 255] })
+        .collect();
+    let mut floor_texture = Texture::from_rgba8(64, 64, checker);
+    floor_texture.generate_mips();
+    let floor_shader = TextureFragmentShader { texture: &floor_texture, sampler: Sampler::anisotropic(16) };
+    let mut floor_pipeline = PipelineState::new(BasicVertexShader, floor_shader);
+    floor_pipeline.topology = PrimitiveTopology::TriangleStrip;
+
+    // A soft shadow under the cube, drawn onto the floor as a decal
+    let shadow = [
+        Vertex::new(Vec3::new(-1.6, 2.4, -1.5), up, Vec2::new(0.0, 0.0)),
+        Vertex::new(Vec3::new(1.6, 2.4, -1.5), up, Vec2::new(1.0, 0.0)),
+        Vertex::new(Vec3::new(-1.6, 5.6, -1.5), up, Vec2::new(0.0, 1.0)),
+        Vertex::new(Vec3::new(1.6, 5.6, -1.5), up, Vec2::new(1.0, 1.0)),
+    ];
+    let blob = (0..32 * 32)
+        .map(|i| {
+            let (x, y) = ((i % 32) as f32 - 15.5, (i / 32) as f32 - 15.5);
+            let alpha = (1.0 - (x * x + y * y).sqrt() / 16.0).clamp(0.0, 1.0);
+            [0, 0, 0, (alpha * 160.0) as u8]
+        })
+        .collect();
+    let shadow_texture = Texture::from_rgba8(32, 32, blob);
+    let shadow_shader = TextureFragmentShader { texture: &shadow_texture, sampler: Sampler::LINEAR };
+    let mut shadow_pipeline = PipelineState::new(BasicVertexShader, shadow_shader);
+    shadow_pipeline.topology = PrimitiveTopology::TriangleStrip;
+    shadow_pipeline.blend = BlendState::ALPHA;
+    shadow_pipeline.depth_write = false;
+    // Pulls the decal towards the camera so it passes the depth test against the floor it lies on
+    shadow_pipeline.depth_bias = DepthBias::new(-1.0, -1.0);
+
     rl.set_target_fps(144);
     while !rl.window_should_close() {
         renderer.clear_color(Vec4::new(0.258824, 0.258824, 0.435294, 1.0f32));
+        renderer.clear_depth(1.0);
+        renderer.clear_stencil(0);
 
         let model = ModelTransform::new(Vec3::new(0.0, 0.0, 4.0), yaw, 0.0);
         yaw += 0.2 * delta_t as f32;
@@ -45,8 +160,24 @@ fn main() {
         let camera = CameraTransform::new(camera_pos, 0.0, 0.0);
         let persp = WorldToScreenTransform::new(120.0, 1280.0, 720.0, 0.1, 100.0);
 
-        renderer.draw_triangles(&cube.verts, &cube.indices, model, camera, persp);
+        let floor_uniforms = Uniforms::new(ModelTransform::new(Vec3::ZERO, 0.0, 0.0), camera.clone(), persp.clone());
+        renderer.draw(&floor_pipeline, &floor, &floor_uniforms);
+        renderer.draw(&shadow_pipeline, &shadow, &floor_uniforms);
+
+        let uniforms = Uniforms::new(model, camera, persp);
+        if pipeline.polygon_mode == PolygonMode::Point {
+            renderer.draw_indexed(&dot_pipeline, &cube.verts, &cube.indices, &uniforms);
+        } else {
+            renderer.draw_indexed(&pipeline, &cube.verts, &cube.indices, &uniforms);
+        }
+
+        // A row of cubes further back, all drawn with a single instanced draw
+        let instances: Vec<Instance> = (0..7)
+            .map(|i| Instance::new(ModelTransform::new(Vec3::new(i as f32 * 4.0 - 12.0, -0.5, 14.0), yaw + i as f32 * 0.5, 0.0)))
+            .collect();
+        renderer.draw_instanced(&instance_pipeline, &cube.verts, &cube.indices, &instances, &uniforms);
 
+        renderer.target.resolve();
         let pixels = renderer.target.color_buffer_to_pixels();
 
         {
@@ -57,6 +188,56 @@ fn main() {
             d.draw_texture(&backbuffer_texture, 0, 0, color::rcolor(0xFF, 0xFF, 0xFF, 0xFF));
         }
 
+        if rl.is_key_pressed(KeyboardKey::KEY_TAB)
+        {
+            pipeline.polygon_mode = match pipeline.polygon_mode {
+                PolygonMode::Fill => PolygonMode::Line,
+                PolygonMode::Line => PolygonMode::Point,
+                PolygonMode::Point => PolygonMode::Fill,
+            };
+            floor_pipeline.polygon_mode = pipeline.polygon_mode;
+            shadow_pipeline.polygon_mode = pipeline.polygon_mode;
+            instance_pipeline.polygon_mode = pipeline.polygon_mode;
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_C)
+        {
+            // Toggles clipping everything to the middle of the window
+            let scissor = match pipeline.scissor {
+                Some(_) => None,
+                None => Some(ScissorRect::new(WIDTH / 4, HEIGHT / 4, WIDTH / 2, HEIGHT / 2)),
+            };
+            pipeline.scissor = scissor;
+            floor_pipeline.scissor = scissor;
+            shadow_pipeline.scissor = scissor;
+            instance_pipeline.scissor = scissor;
+            dot_pipeline.scissor = scissor;
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_L)
+        {
+            // Toggles anti-aliased lines, their coverage only shows when blended by alpha
+            let (line_mode, blend) = match pipeline.line_mode {
+                LineMode::Bresenham => (LineMode::XiaolinWu, BlendState::ALPHA),
+                LineMode::XiaolinWu => (LineMode::Bresenham, BlendState::REPLACE),
+            };
+            pipeline.line_mode = line_mode;
+            pipeline.blend = blend;
+            floor_pipeline.line_mode = line_mode;
+            floor_pipeline.blend = blend;
+            instance_pipeline.line_mode = line_mode;
+            instance_pipeline.blend = blend;
+        }
+        // 1, 2, 4 and 8 pick how many samples each pixel gets
+        let sample_keys = [
+            (KeyboardKey::KEY_ONE, SampleCount::X1),
+            (KeyboardKey::KEY_TWO, SampleCount::X2),
+            (KeyboardKey::KEY_FOUR, SampleCount::X4),
+            (KeyboardKey::KEY_EIGHT, SampleCount::X8),
+        ];
+        for (key, samples) in sample_keys {
+            if rl.is_key_pressed(key) && renderer.target.samples != samples {
+                renderer = Renderer::new_multisampled(WIDTH, HEIGHT, samples);
+            }
+        }
         if rl.is_key_down(KeyboardKey::KEY_W)
         {
             camera_pos.z += delta_t;
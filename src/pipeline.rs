@@ -0,0 +1,479 @@
+use crate::shader::*;
+use crate::vmath::*;
+use crate::transform::Viewport;
+
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+    GreaterEqual,
+    Greater,
+    Always,
+}
+
+impl CompareFunc {
+    #[inline]
+    pub fn test<T: PartialOrd>(self, value: T, reference: T) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => value < reference,
+            CompareFunc::LessEqual => value <= reference,
+            CompareFunc::Equal => value == reference,
+            CompareFunc::NotEqual => value != reference,
+            CompareFunc::GreaterEqual => value >= reference,
+            CompareFunc::Greater => value > reference,
+            CompareFunc::Always => true,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    IncrementClamp,
+    DecrementClamp,
+    Invert,
+    IncrementWrap,
+    DecrementWrap,
+}
+
+impl StencilOp {
+    #[inline]
+    pub fn apply(self, value: u8, reference: u8) -> u8 {
+        match self {
+            StencilOp::Keep => value,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => reference,
+            StencilOp::IncrementClamp => value.saturating_add(1),
+            StencilOp::DecrementClamp => value.saturating_sub(1),
+            StencilOp::Invert => !value,
+            StencilOp::IncrementWrap => value.wrapping_add(1),
+            StencilOp::DecrementWrap => value.wrapping_sub(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct StencilState {
+    pub enabled: bool,
+    /// Passes when `(reference & read_mask) func (stencil & read_mask)`.
+    pub func: CompareFunc,
+    pub reference: u8,
+    pub read_mask: u8,
+    /// Only these bits of the stencil buffer are changed by the ops.
+    pub write_mask: u8,
+    pub fail_op: StencilOp,
+    pub depth_fail_op: StencilOp,
+    pub pass_op: StencilOp,
+}
+
+impl StencilState {
+    pub const DISABLED: Self = Self {
+        enabled: false,
+        func: CompareFunc::Always,
+        reference: 0,
+        read_mask: 0xFF,
+        write_mask: 0xFF,
+        fail_op: StencilOp::Keep,
+        depth_fail_op: StencilOp::Keep,
+        pass_op: StencilOp::Keep,
+    };
+
+    #[inline]
+    pub fn test(&self, stencil: u8) -> bool {
+        self.func.test(self.reference & self.read_mask, stencil & self.read_mask)
+    }
+
+    /// New stencil value after applying `op`, respecting the write mask.
+    #[inline]
+    pub fn update(&self, stencil: u8, op: StencilOp) -> u8 {
+        (stencil & !self.write_mask) | (op.apply(stencil, self.reference) & self.write_mask)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum CullMode {
+    None,
+    Front,
+    Back,
+}
+
+/// Winding order of front facing triangles in screen space (x right, y up).
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrontFace {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+    ConstantColor,
+    OneMinusConstantColor,
+    SrcAlphaSaturate,
+}
+
+impl BlendFactor {
+    #[inline]
+    pub fn factor(self, src: Vec4, dst: Vec4, constant: Vec4) -> Vec4 {
+        match self {
+            BlendFactor::Zero => Vec4::ZERO,
+            BlendFactor::One => Vec4::ONE,
+            BlendFactor::SrcColor => src,
+            BlendFactor::OneMinusSrcColor => Vec4::ONE - src,
+            BlendFactor::DstColor => dst,
+            BlendFactor::OneMinusDstColor => Vec4::ONE - dst,
+            BlendFactor::SrcAlpha => Vec4::splat(src.w),
+            BlendFactor::OneMinusSrcAlpha => Vec4::splat(1.0 - src.w),
+            BlendFactor::DstAlpha => Vec4::splat(dst.w),
+            BlendFactor::OneMinusDstAlpha => Vec4::splat(1.0 - dst.w),
+            BlendFactor::ConstantColor => constant,
+            BlendFactor::OneMinusConstantColor => Vec4::ONE - constant,
+            BlendFactor::SrcAlphaSaturate => {
+                let f = src.w.min(1.0 - dst.w);
+                Vec4::new(f, f, f, 1.0)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlendOp {
+    Add,
+    /// Source minus destination.
+    Subtract,
+    /// Destination minus source.
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+impl BlendOp {
+    /// Min and max ignore the blend factors, same as OpenGL and D3D.
+    #[inline]
+    pub fn apply(self, src: f32, src_factor: f32, dst: f32, dst_factor: f32) -> f32 {
+        match self {
+            BlendOp::Add => src * src_factor + dst * dst_factor,
+            BlendOp::Subtract => src * src_factor - dst * dst_factor,
+            BlendOp::ReverseSubtract => dst * dst_factor - src * src_factor,
+            BlendOp::Min => src.min(dst),
+            BlendOp::Max => src.max(dst),
+        }
+    }
+}
+
+/// How fragment colours are combined with what is already in the colour buffer.
+/// Colour (rgb) and alpha each get their own factors and equation.
+#[derive(Clone, Copy, PartialEq)]
+pub struct BlendState {
+    pub enabled: bool,
+    pub src_color: BlendFactor,
+    pub dst_color: BlendFactor,
+    pub color_op: BlendOp,
+    pub src_alpha: BlendFactor,
+    pub dst_alpha: BlendFactor,
+    pub alpha_op: BlendOp,
+    /// Used by the `ConstantColor` factors.
+    pub constant: Vec4,
+}
+
+impl BlendState {
+    /// Fragments overwrite the colour buffer.
+    pub const REPLACE: Self = Self {
+        enabled: false,
+        src_color: BlendFactor::One,
+        dst_color: BlendFactor::Zero,
+        color_op: BlendOp::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::Zero,
+        alpha_op: BlendOp::Add,
+        constant: Vec4::ZERO,
+    };
+
+    /// Classic "over" compositing with straight alpha.
+    pub const ALPHA: Self = Self {
+        enabled: true,
+        src_color: BlendFactor::SrcAlpha,
+        dst_color: BlendFactor::OneMinusSrcAlpha,
+        color_op: BlendOp::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::OneMinusSrcAlpha,
+        alpha_op: BlendOp::Add,
+        constant: Vec4::ZERO,
+    };
+
+    /// "Over" compositing for colours already multiplied by their alpha.
+    #[allow(dead_code)]
+    pub const PREMULTIPLIED_ALPHA: Self = Self {
+        src_color: BlendFactor::One,
+        ..Self::ALPHA
+    };
+
+    /// Adds the fragment on top, weighted by its alpha, for particles and glows.
+    #[allow(dead_code)]
+    pub const ADDITIVE: Self = Self {
+        enabled: true,
+        src_color: BlendFactor::SrcAlpha,
+        dst_color: BlendFactor::One,
+        color_op: BlendOp::Add,
+        src_alpha: BlendFactor::One,
+        dst_alpha: BlendFactor::One,
+        alpha_op: BlendOp::Add,
+        constant: Vec4::ZERO,
+    };
+
+    #[inline]
+    pub fn blend(&self, src: Vec4, dst: Vec4) -> Vec4 {
+        if !self.enabled {
+            return src;
+        }
+
+        let src_color = self.src_color.factor(src, dst, self.constant);
+        let dst_color = self.dst_color.factor(src, dst, self.constant);
+        let src_alpha = self.src_alpha.factor(src, dst, self.constant).w;
+        let dst_alpha = self.dst_alpha.factor(src, dst, self.constant).w;
+
+        Vec4::new(
+            self.color_op.apply(src.x, src_color.x, dst.x, dst_color.x),
+            self.color_op.apply(src.y, src_color.y, dst.y, dst_color.y),
+            self.color_op.apply(src.z, src_color.z, dst.z, dst_color.z),
+            self.alpha_op.apply(src.w, src_alpha, dst.w, dst_alpha),
+        )
+    }
+}
+
+/// Offset added to the depth of triangles before the depth test, to keep coplanar geometry like
+/// decals or wireframe overlays from fighting with what is below it.
+#[derive(Clone, Copy)]
+pub struct DepthBias {
+    /// In steps of the smallest depth difference representable at the triangle's depth.
+    pub constant: f32,
+    /// Scales the largest change in depth per pixel across the triangle.
+    pub slope_scale: f32,
+    /// Upper limit of the offset when positive, lower limit when negative, 0 for no limit.
+    pub clamp: f32,
+}
+
+impl DepthBias {
+    pub const NONE: Self = Self { constant: 0.0, slope_scale: 0.0, clamp: 0.0 };
+
+    pub const fn new(constant: f32, slope_scale: f32) -> Self {
+        Self { constant: constant, slope_scale: slope_scale, clamp: 0.0 }
+    }
+
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        self.constant == 0.0 && self.slope_scale == 0.0
+    }
+
+    /// The offset for a triangle whose largest depth is `max_depth` and changes at most `slope` per pixel.
+    pub fn offset(&self, max_depth: f32, slope: f32) -> f32 {
+        // 2^(e - 23) for a depth of 2^e * 1.m, the precision of an f32 at that depth
+        let exponent = (max_depth.abs().to_bits() >> 23) & 0xff;
+        let unit = f32::from_bits(exponent.saturating_sub(23) << 23);
+
+        let bias = self.constant * unit + self.slope_scale * slope;
+        if self.clamp > 0.0 {
+            bias.min(self.clamp)
+        } else if self.clamp < 0.0 {
+            bias.max(self.clamp)
+        } else {
+            bias
+        }
+    }
+}
+
+/// How the vertices of a draw are assembled into primitives.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum PrimitiveTopology {
+    /// Every three vertices form a triangle.
+    TriangleList,
+    /// Every vertex forms a triangle with the two before it.
+    TriangleStrip,
+    /// Every vertex forms a triangle with the one before it and the first one.
+    TriangleFan,
+    /// Every two vertices form a line.
+    LineList,
+    /// Every vertex forms a line with the one before it.
+    LineStrip,
+    PointList,
+}
+
+/// How triangles are drawn.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PolygonMode {
+    Fill,
+    /// Only the edges, as lines.
+    Line,
+    /// Only the vertices, as points.
+    Point,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineMode {
+    Bresenham,
+    /// Anti-aliased, the coverage of each pixel is multiplied into the alpha of its colour,
+    /// so it only shows with a blend state that uses the source alpha.
+    XiaolinWu,
+}
+
+/// Pixels outside of this rectangle are never touched, given from the bottom left corner of the render target.
+#[derive(Clone, Copy)]
+pub struct ScissorRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl ScissorRect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Self { x: x, y: y, width: width, height: height }
+    }
+}
+
+/// Everything that decides how a draw gets processed besides its buffers and uniforms.
+/// Meant to be set up once and reused for every draw that shares the same shaders and state.
+#[derive(Clone)]
+pub struct PipelineState<V: VertexShader, F: FragmentShader> {
+    pub vertex_shader: V,
+    pub fragment_shader: F,
+
+    pub depth_func: CompareFunc,
+    pub depth_write: bool,
+    pub depth_bias: DepthBias,
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    pub stencil: StencilState,
+    pub blend: BlendState,
+    pub topology: PrimitiveTopology,
+    pub polygon_mode: PolygonMode,
+    pub line_mode: LineMode,
+    /// Thickness of lines in pixels along their minor axis.
+    pub line_width: f32,
+    /// Width and height of point sprites in pixels.
+    pub point_size: f32,
+    /// `None` covers the whole render target.
+    pub viewport: Option<Viewport>,
+    pub scissor: Option<ScissorRect>,
+}
+
+impl<V: VertexShader, F: FragmentShader> PipelineState<V, F> {
+    pub fn new(vertex_shader: V, fragment_shader: F) -> Self {
+        Self {
+            vertex_shader: vertex_shader,
+            fragment_shader: fragment_shader,
+
+            depth_func: CompareFunc::Less,
+            depth_write: true,
+            depth_bias: DepthBias::NONE,
+            cull_mode: CullMode::Back,
+            front_face: FrontFace::CounterClockwise,
+            stencil: StencilState::DISABLED,
+            blend: BlendState::REPLACE,
+            topology: PrimitiveTopology::TriangleList,
+            polygon_mode: PolygonMode::Fill,
+            line_mode: LineMode::Bresenham,
+            line_width: 1.0,
+            point_size: 1.0,
+            viewport: None,
+            scissor: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec4, b: Vec4) -> bool {
+        (a.x - b.x).abs() < 1e-6 && (a.y - b.y).abs() < 1e-6 && (a.z - b.z).abs() < 1e-6 && (a.w - b.w).abs() < 1e-6
+    }
+
+    #[test]
+    fn blend_factors_match_their_definitions() {
+        let src = Vec4::new(0.2, 0.4, 0.6, 0.8);
+        let dst = Vec4::new(0.5, 0.25, 0.75, 0.5);
+        let constant = Vec4::new(0.1, 0.3, 0.5, 0.7);
+
+        let expected = [
+            (BlendFactor::Zero, Vec4::ZERO),
+            (BlendFactor::One, Vec4::ONE),
+            (BlendFactor::SrcColor, Vec4::new(0.2, 0.4, 0.6, 0.8)),
+            (BlendFactor::OneMinusSrcColor, Vec4::new(0.8, 0.6, 0.4, 0.2)),
+            (BlendFactor::DstColor, Vec4::new(0.5, 0.25, 0.75, 0.5)),
+            (BlendFactor::OneMinusDstColor, Vec4::new(0.5, 0.75, 0.25, 0.5)),
+            (BlendFactor::SrcAlpha, Vec4::splat(0.8)),
+            (BlendFactor::OneMinusSrcAlpha, Vec4::splat(0.2)),
+            (BlendFactor::DstAlpha, Vec4::splat(0.5)),
+            (BlendFactor::OneMinusDstAlpha, Vec4::splat(0.5)),
+            (BlendFactor::ConstantColor, Vec4::new(0.1, 0.3, 0.5, 0.7)),
+            (BlendFactor::OneMinusConstantColor, Vec4::new(0.9, 0.7, 0.5, 0.3)),
+            // min(0.8, 1 - 0.5) for the colour, alpha is always one
+            (BlendFactor::SrcAlphaSaturate, Vec4::new(0.5, 0.5, 0.5, 1.0)),
+        ];
+        for (factor, value) in expected {
+            assert!(close(factor.factor(src, dst, constant), value));
+        }
+    }
+
+    #[test]
+    fn blend_ops_match_their_definitions() {
+        // 0.2 * 0.5 from the source and 0.6 * 0.25 from the destination
+        let expected = [
+            (BlendOp::Add, 0.25),
+            (BlendOp::Subtract, -0.05),
+            (BlendOp::ReverseSubtract, 0.05),
+            (BlendOp::Min, 0.2),
+            (BlendOp::Max, 0.6),
+        ];
+        for (op, value) in expected {
+            assert!((op.apply(0.2, 0.5, 0.6, 0.25) - value).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn blend_states_combine_colour_and_alpha_separately() {
+        let dst = Vec4::new(0.0, 0.0, 1.0, 1.0);
+        assert!(BlendState::REPLACE.blend(Vec4::new(1.0, 0.0, 0.0, 0.25), dst) == Vec4::new(1.0, 0.0, 0.0, 0.25));
+        assert!(close(BlendState::ALPHA.blend(Vec4::new(1.0, 0.0, 0.0, 0.25), dst), Vec4::new(0.25, 0.0, 0.75, 1.0)));
+        assert!(close(BlendState::PREMULTIPLIED_ALPHA.blend(Vec4::new(0.25, 0.0, 0.0, 0.25), dst), Vec4::new(0.25, 0.0, 0.75, 1.0)));
+        assert!(close(BlendState::ADDITIVE.blend(Vec4::new(0.5, 0.5, 0.0, 0.5), Vec4::new(0.25, 0.25, 0.25, 0.5)), Vec4::new(0.5, 0.5, 0.25, 1.0)));
+
+        // Darkens the colour by a constant while keeping the larger alpha
+        let state = BlendState {
+            enabled: true,
+            src_color: BlendFactor::ConstantColor,
+            dst_color: BlendFactor::One,
+            color_op: BlendOp::ReverseSubtract,
+            src_alpha: BlendFactor::Zero,
+            dst_alpha: BlendFactor::Zero,
+            alpha_op: BlendOp::Max,
+            constant: Vec4::new(0.5, 0.5, 0.5, 0.0),
+        };
+        let blended = state.blend(Vec4::new(0.5, 1.0, 0.25, 0.75), Vec4::new(0.5, 0.75, 0.5, 0.5));
+        assert!(close(blended, Vec4::new(0.25, 0.25, 0.375, 0.75)));
+    }
+}
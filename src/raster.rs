@@ -0,0 +1,370 @@
+use crate::vmath::*;
+
+
+/// Vertex positions are snapped to a 1/256th of a pixel grid before edge setup.
+pub const SUBPIXEL_BITS: i32 = 8;
+pub const SUBPIXEL_ONE: i64 = 1 << SUBPIXEL_BITS;
+pub const SUBPIXEL_HALF: i64 = SUBPIXEL_ONE / 2;
+
+/// A screen position in sub-pixel fixed point.
+#[derive(Clone, Copy, PartialEq)]
+pub struct FixedPoint {
+    pub x: i64,
+    pub y: i64,
+}
+
+impl FixedPoint {
+    #[inline]
+    pub const fn new(x: i64, y: i64) -> Self {
+        Self { x: x, y: y }
+    }
+
+    #[inline]
+    pub fn snap(p: Vec2) -> Self {
+        Self {
+            x: (p.x * SUBPIXEL_ONE as f32).round() as i64,
+            y: (p.y * SUBPIXEL_ONE as f32).round() as i64,
+        }
+    }
+
+    /// Centre of the pixel at (x, y).
+    #[inline]
+    pub const fn pixel_center(x: i32, y: i32) -> Self {
+        Self {
+            x: x as i64 * SUBPIXEL_ONE + SUBPIXEL_HALF,
+            y: y as i64 * SUBPIXEL_ONE + SUBPIXEL_HALF,
+        }
+    }
+
+    /// The pixel containing this position.
+    #[inline]
+    pub const fn pixel(self) -> IVec2 {
+        IVec2::new((self.x >> SUBPIXEL_BITS) as i32, (self.y >> SUBPIXEL_BITS) as i32)
+    }
+}
+
+/// Twice the signed area of (a, b, p), exact in fixed point.
+#[inline]
+pub fn edge_function_fixed(a: FixedPoint, b: FixedPoint, p: FixedPoint) -> i64 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+/// Whether a → b is a top or left edge of a counter-clockwise triangle (x right, y up).
+/// Going counter-clockwise, left edges point down and top edges point left.
+#[inline]
+pub fn is_top_left(a: FixedPoint, b: FixedPoint) -> bool {
+    let dx = b.x - a.x;
+    let dy = b.y - a.y;
+
+    dy < 0 || (dy == 0 && dx < 0)
+}
+
+/// Samples exactly on an edge only belong to the triangle if the edge is top-left, so pixels
+/// on an edge shared by two triangles are covered by exactly one of them.
+/// A sample is covered when `edge_function_fixed + fill_rule_bias >= 0`.
+#[inline]
+pub fn fill_rule_bias(a: FixedPoint, b: FixedPoint) -> i64 {
+    if is_top_left(a, b) { 0 } else { -1 }
+}
+
+/// Edge function of a → b stepped incrementally across the pixel grid.
+#[derive(Clone, Copy)]
+pub struct EdgeEquation {
+    /// Change in value when moving one pixel right.
+    pub step_x: i64,
+    /// Change in value when moving one pixel up.
+    pub step_y: i64,
+    pub bias: i64,
+    a: FixedPoint,
+    b: FixedPoint,
+}
+
+impl EdgeEquation {
+    pub fn new(a: FixedPoint, b: FixedPoint) -> Self {
+        Self {
+            step_x: -(b.y - a.y) * SUBPIXEL_ONE,
+            step_y: (b.x - a.x) * SUBPIXEL_ONE,
+            bias: fill_rule_bias(a, b),
+            a: a,
+            b: b,
+        }
+    }
+
+    #[inline]
+    pub fn evaluate(&self, p: FixedPoint) -> i64 {
+        edge_function_fixed(self.a, self.b, p)
+    }
+}
+
+/// Per triangle setup for the edge walking rasterizer. Edge `i` is the one opposite vertex `i`,
+/// so its value divided by the area is the barycentric weight of that vertex.
+pub struct TriangleSetup {
+    pub edges: [EdgeEquation; 3],
+    pub inv_area: f32,
+    /// Change of the barycentrics per pixel right and up.
+    pub bary_ddx: Vec3,
+    pub bary_ddy: Vec3,
+    pub bound_min: IVec2,
+    pub bound_max: IVec2,
+}
+
+impl TriangleSetup {
+    /// Returns `None` for triangles that are not counter-clockwise after snapping,
+    /// since snapping can collapse tiny triangles or flip slivers and those have no coverage.
+    pub fn new(p1: FixedPoint, p2: FixedPoint, p3: FixedPoint) -> Option<Self> {
+        let area = edge_function_fixed(p1, p2, p3);
+        if area <= 0 {
+            return None;
+        }
+
+        let edges = [EdgeEquation::new(p2, p3), EdgeEquation::new(p3, p1), EdgeEquation::new(p1, p2)];
+        let inv_area = 1.0 / area as f32;
+
+        Some(Self {
+            edges: edges,
+            inv_area: inv_area,
+            bary_ddx: Vec3::new(edges[0].step_x as f32, edges[1].step_x as f32, edges[2].step_x as f32) * inv_area,
+            bary_ddy: Vec3::new(edges[0].step_y as f32, edges[1].step_y as f32, edges[2].step_y as f32) * inv_area,
+            bound_min: FixedPoint::new(p1.x.min(p2.x.min(p3.x)), p1.y.min(p2.y.min(p3.y))).pixel(),
+            bound_max: FixedPoint::new(p1.x.max(p2.x.max(p3.x)), p1.y.max(p2.y.max(p3.y))).pixel(),
+        })
+    }
+
+    /// Edge values at the centre of pixel (x, y).
+    #[inline]
+    pub fn evaluate(&self, x: i32, y: i32) -> [i64; 3] {
+        let p = FixedPoint::pixel_center(x, y);
+        [self.edges[0].evaluate(p), self.edges[1].evaluate(p), self.edges[2].evaluate(p)]
+    }
+
+    #[inline]
+    pub fn barycentrics(&self, e: [i64; 3]) -> Vec3 {
+        Vec3::new(e[0] as f32 * self.inv_area, e[1] as f32 * self.inv_area, e[2] as f32 * self.inv_area)
+    }
+}
+
+/// Walks the pixels of the line from `from` to `to`, both included, with Bresenham's algorithm.
+/// Wide lines repeat every pixel `width` times along the minor axis. Only pixels inside the
+/// inclusive rectangle `min`..`max` are passed to `plot`, along with their position along the line.
+pub fn bresenham(from: IVec2, to: IVec2, width: i32, min: IVec2, max: IVec2, mut plot: impl FnMut(i32, i32, f32)) {
+    // Walk along the major axis, a, stepping the minor axis, b, as the error builds up
+    let steep = (to.y - from.y).abs() > (to.x - from.x).abs();
+    let axes = |p: IVec2| if steep { (p.y, p.x) } else { (p.x, p.y) };
+
+    let reversed = axes(from).0 > axes(to).0;
+    let (start, end) = if reversed { (to, from) } else { (from, to) };
+    let (a0, b0) = axes(start);
+    let (a1, b1) = axes(end);
+    let (min_a, min_b) = axes(min);
+    let (max_a, max_b) = axes(max);
+
+    let da = (a1 - a0) as i64;
+    let db = (b1 - b0).abs() as i64;
+    let step_b = (b1 - b0).signum();
+
+    let first = a0.max(min_a);
+    let last = a1.min(max_a);
+    if first > last {
+        return;
+    }
+
+    // Jump straight to the first visible step, the minor offset after k steps is round(k * db / da)
+    let k = (first - a0) as i64;
+    let (mut b, mut error) = if da == 0 {
+        (b0, 0)
+    } else {
+        let num = 2 * k * db + da;
+        (b0 + step_b * (num / (2 * da)) as i32, num % (2 * da))
+    };
+
+    for a in first..=last {
+        let mut t = if da == 0 { 0.0 } else { (a - a0) as f32 / da as f32 };
+        if reversed {
+            t = 1.0 - t;
+        }
+
+        let span = b - (width - 1) / 2;
+        for minor in span.max(min_b)..=(span + width - 1).min(max_b) {
+            if steep { plot(minor, a, t) } else { plot(a, minor, t) }
+        }
+
+        error += 2 * db;
+        if error >= 2 * da {
+            error -= 2 * da;
+            b += step_b;
+        }
+    }
+}
+
+/// Walks the pixels of an anti-aliased line between two screen positions with Xiaolin Wu's algorithm.
+/// Coverage is the area of the pixel overlapped by the line, `width` pixels thick along the minor axis.
+/// Only pixels inside the inclusive rectangle `min`..`max` are passed to `plot`, along with their
+/// position along the line and their coverage.
+pub fn xiaolin_wu(from: Vec2, to: Vec2, width: f32, min: IVec2, max: IVec2, mut plot: impl FnMut(i32, i32, f32, f32)) {
+    let steep = (to.y - from.y).abs() > (to.x - from.x).abs();
+    // Pixel centres land on whole numbers, so rounding gives the pixel a position is in
+    let axes = |p: Vec2| if steep { (p.y - 0.5, p.x - 0.5) } else { (p.x - 0.5, p.y - 0.5) };
+
+    let reversed = axes(from).0 > axes(to).0;
+    let (start, end) = if reversed { (to, from) } else { (from, to) };
+    let (a0, b0) = axes(start);
+    let (a1, b1) = axes(end);
+    let (min_a, min_b) = if steep { (min.y, min.x) } else { (min.x, min.y) };
+    let (max_a, max_b) = if steep { (max.y, max.x) } else { (max.x, max.y) };
+
+    let da = a1 - a0;
+    if da <= 0.0 {
+        return;
+    }
+    let gradient = (b1 - b0) / da;
+    let half_width = width * 0.5;
+
+    let first = (a0.round() as i32).max(min_a);
+    let last = (a1.round() as i32).min(max_a);
+    for a in first..=last {
+        // Only the end pixels are partially covered along the major axis
+        let major = (a as f32 + 0.5).min(a1) - (a as f32 - 0.5).max(a0);
+        if major <= 0.0 {
+            continue;
+        }
+
+        let mut t = clamp((a as f32 - a0) / da, 0.0, 1.0);
+        if reversed {
+            t = 1.0 - t;
+        }
+
+        let center = b0 + gradient * (a as f32 - a0);
+        let low = center - half_width;
+        let high = center + half_width;
+        for minor in (low.round() as i32).max(min_b)..=(high.round() as i32).min(max_b) {
+            let coverage = major.min(1.0) * ((minor as f32 + 0.5).min(high) - (minor as f32 - 0.5).max(low));
+            if coverage > 0.0 {
+                if steep { plot(minor, a, t, coverage) } else { plot(a, minor, t, coverage) }
+            }
+        }
+    }
+}
+
+/// Walks the pixels whose centres fall within the square point sprite of `size` pixels around `center`.
+/// Only pixels inside the inclusive rectangle `min`..`max` are passed to `plot`, along with the
+/// position within the sprite, from (0, 0) in the bottom left to (1, 1) in the top right.
+pub fn point_sprite(center: Vec2, size: f32, min: IVec2, max: IVec2, mut plot: impl FnMut(i32, i32, Vec2)) {
+    let corner = center - Vec2::splat(size * 0.5);
+    let first = IVec2::new((corner.x - 0.5).ceil() as i32, (corner.y - 0.5).ceil() as i32);
+    let last = IVec2::new((corner.x + size - 0.5).ceil() as i32 - 1, (corner.y + size - 0.5).ceil() as i32 - 1);
+
+    for y in first.y.max(min.y)..=last.y.min(max.y) {
+        for x in first.x.max(min.x)..=last.x.min(max.x) {
+            plot(x, y, Vec2::new((x as f32 + 0.5 - corner.x) / size, (y as f32 + 0.5 - corner.y) / size));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_top_and_left_edges_keep_their_samples() {
+        // Counter-clockwise around a square, then a triangle with sloped edges, only exactly horizontal edges
+        // can be top edges, sloped ones are left edges when they point down
+        let [a, b, c, d] = [FixedPoint::new(0, 0), FixedPoint::new(256, 0), FixedPoint::new(256, 256), FixedPoint::new(0, 256)];
+        assert!(!is_top_left(a, b) && !is_top_left(b, c) && is_top_left(c, d) && is_top_left(d, a));
+
+        let [e, f, g] = [FixedPoint::new(0, 0), FixedPoint::new(256, 128), FixedPoint::new(-64, 512)];
+        assert!(!is_top_left(e, f) && !is_top_left(f, g) && is_top_left(g, e));
+        assert!(fill_rule_bias(a, b) == -1 && fill_rule_bias(c, d) == 0);
+    }
+
+    /// Whether the centre of pixel (10, 10) is covered by a triangle whose left edge runs straight up at `x`.
+    fn covers_pixel_10(x: f32) -> bool {
+        let setup = TriangleSetup::new(
+            FixedPoint::snap(Vec2::new(x, 0.0)),
+            FixedPoint::snap(Vec2::new(20.0, 10.0)),
+            FixedPoint::snap(Vec2::new(x, 20.0)),
+        ).unwrap();
+
+        let e = setup.evaluate(10, 10);
+        (0..3).all(|i| e[i] + setup.edges[i].bias >= 0)
+    }
+
+    #[test]
+    fn vertices_snap_to_the_subpixel_grid() {
+        assert!(FixedPoint::snap(Vec2::new(1.0, -2.5)) == FixedPoint::new(256, -640));
+        assert!(FixedPoint::snap(Vec2::new(1.0 + 0.4 / 256.0, 1.0 + 0.6 / 256.0)) == FixedPoint::new(256, 257));
+
+        // Exactly on the pixel centre the left edge keeps it, within half a sub-pixel it still lands there
+        assert!(covers_pixel_10(10.5));
+        assert!(covers_pixel_10(10.5 + 0.4 / 256.0));
+        assert!(!covers_pixel_10(10.5 + 0.6 / 256.0));
+        assert!(covers_pixel_10(10.5 - 0.6 / 256.0));
+    }
+
+    const MIN: IVec2 = IVec2::new(-100, -100);
+    const MAX: IVec2 = IVec2::new(100, 100);
+
+    /// Pixels plotted by `bresenham`, in order.
+    fn bresenham_pixels(from: IVec2, to: IVec2, width: i32) -> Vec<(i32, i32)> {
+        let mut pixels = Vec::new();
+        bresenham(from, to, width, MIN, MAX, |x, y, _| pixels.push((x, y)));
+        pixels
+    }
+
+    #[test]
+    fn wide_bresenham_lines_repeat_along_the_minor_axis() {
+        for width in 1..=4 {
+            let horizontal = bresenham_pixels(IVec2::new(0, 0), IVec2::new(9, 0), width);
+            assert!(horizontal.len() == 10 * width as usize);
+            let spans = horizontal.iter().filter(|&&(x, _)| x == 4).count();
+            assert!(spans == width as usize);
+
+            let steep = bresenham_pixels(IVec2::new(0, 0), IVec2::new(3, 9), width);
+            assert!(steep.len() == 10 * width as usize);
+            assert!(steep.iter().all(|&(_, y)| (0..=9).contains(&y)));
+        }
+
+        // Odd widths are centred on the line
+        let wide = bresenham_pixels(IVec2::new(0, 0), IVec2::new(9, 9), 3);
+        assert!((0..=9).all(|a| [-1, 0, 1].iter().all(|d| wide.contains(&(a, a + d)))));
+    }
+
+    /// Coverage of every pixel plotted by `xiaolin_wu`, by pixel.
+    fn wu_coverage(from: Vec2, to: Vec2, width: f32) -> Vec<((i32, i32), f32)> {
+        let mut pixels = Vec::new();
+        xiaolin_wu(from, to, width, MIN, MAX, |x, y, _, coverage| pixels.push(((x, y), coverage)));
+        pixels
+    }
+
+    #[test]
+    fn xiaolin_wu_splits_coverage_between_neighbours() {
+        // Halfway between two rows of pixel centres, so every column is split evenly between them
+        let diagonal = wu_coverage(Vec2::new(0.5, 1.0), Vec2::new(8.5, 9.0), 1.0);
+        for a in 1..8 {
+            let below = diagonal.iter().find(|(p, _)| *p == (a, a)).unwrap().1;
+            let above = diagonal.iter().find(|(p, _)| *p == (a, a + 1)).unwrap().1;
+            assert!((below - 0.5).abs() < 1e-5 && (above - 0.5).abs() < 1e-5);
+        }
+
+        // Every column covers the width of the line, the end columns only half of it
+        for width in [1.0, 2.5] {
+            let line = wu_coverage(Vec2::new(0.5, 0.5), Vec2::new(8.5, 3.7), width);
+            for a in 0..=8 {
+                let column: f32 = line.iter().filter(|((x, _), _)| *x == a).map(|(_, c)| c).sum();
+                let expected = if a == 0 || a == 8 { width * 0.5 } else { width };
+                assert!((column - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn point_sprite_coords_go_from_bottom_left_to_top_right() {
+        let mut coords = Vec::new();
+        point_sprite(Vec2::new(4.0, 4.0), 4.0, MIN, MAX, |x, y, coord| coords.push(((x, y), coord)));
+
+        assert!(coords.len() == 16);
+        for (pixel, expected) in [((2, 2), (0.125, 0.125)), ((5, 2), (0.875, 0.125)), ((2, 5), (0.125, 0.875)), ((5, 5), (0.875, 0.875))] {
+            let coord = coords.iter().find(|(p, _)| *p == pixel).unwrap().1;
+            assert!(coord == Vec2::new(expected.0, expected.1));
+        }
+    }
+}
@@ -1,88 +1,1452 @@
+use std::sync::Mutex;
 
-use rand::SeedableRng;
-
-use crate::transform::WorldToScreenTransform;
 use crate::vmath::*;
 use crate::render_target::*;
-use crate::transform::{Transform, ModelTransform, CameraTransform};
+use crate::clip::*;
+use crate::raster::*;
+use crate::tiles::*;
+use crate::simd::*;
+use crate::shader::*;
+use crate::pipeline::*;
+use crate::transform::Viewport;
+use crate::workers::WorkerPool;
+
+const UNTRANSFORMED: u32 = u32::MAX;
+
+/// A vertex after the perspective divide, in pixel coordinates with depth in z.
+#[derive(Clone)]
+struct ScreenVertex {
+    position: Vec3,
+    inv_w: f32,
+    varyings: Varyings,
+}
+
+impl ScreenVertex {
+    fn new(clip: &ClipVertex, viewport: &Viewport) -> Self {
+        Self {
+            position: viewport.to_screen(clip.position),
+            inv_w: 1.0 / clip.position.w,
+            varyings: clip.varyings,
+        }
+    }
+}
+
+const NO_DEPTH_OFFSETS: [f32; MAX_SAMPLES] = [0.0; MAX_SAMPLES];
+
+/// The samples of a pixel covered by a primitive, bit `i` being sample `i`, and its depth at each.
+struct SampleCoverage<'a> {
+    mask: u32,
+    /// Depth at the pixel centre.
+    depth: f32,
+    /// Depth of each sample relative to the pixel centre.
+    depth_offsets: &'a [f32; MAX_SAMPLES],
+    /// Lowest and highest depth, see `depth_range`.
+    depth_range: (f32, f32),
+}
+
+impl SampleCoverage<'_> {
+    /// Every sample covered at the same depth, lines and points don't have per sample coverage.
+    #[inline]
+    fn all(sample_count: usize, depth: f32, depth_range: (f32, f32)) -> Self {
+        Self { mask: (1 << sample_count) - 1, depth: depth, depth_offsets: &NO_DEPTH_OFFSETS, depth_range: depth_range }
+    }
+
+    #[inline]
+    fn centre_depth(&self) -> f32 {
+        self.depth.max(self.depth_range.0).min(self.depth_range.1)
+    }
+
+    #[inline]
+    fn sample_depth(&self, sample: usize) -> f32 {
+        (self.depth + self.depth_offsets[sample]).max(self.depth_range.0).min(self.depth_range.1)
+    }
+}
+
+/// A clipped, culled and set up triangle waiting to be rasterized.
+struct RasterTriangle {
+    setup: TriangleSetup,
+    block: BlockSetup,
+    verts: [ScreenVertex; 3],
+}
+
+/// A clipped line waiting to be rasterized, the bounds include its width.
+struct RasterLine {
+    verts: [ScreenVertex; 2],
+    bound_min: IVec2,
+    bound_max: IVec2,
+}
+
+/// A point sprite waiting to be rasterized.
+struct RasterPoint {
+    vert: ScreenVertex,
+    bound_min: IVec2,
+    bound_max: IVec2,
+}
+
+// Triangles are by far the most common, boxing them would cost an allocation each
+#[allow(clippy::large_enum_variant)]
+enum RasterPrimitive {
+    Triangle(RasterTriangle),
+    Line(RasterLine),
+    Point(RasterPoint),
+}
+
+impl RasterPrimitive {
+    /// Inclusive pixel bounds, already clamped to the viewport and scissor rectangle.
+    fn bounds(&self) -> (IVec2, IVec2) {
+        match self {
+            RasterPrimitive::Triangle(tri) => (tri.setup.bound_min, tri.setup.bound_max),
+            RasterPrimitive::Line(line) => (line.bound_min, line.bound_max),
+            RasterPrimitive::Point(point) => (point.bound_min, point.bound_max),
+        }
+    }
+}
 
 pub struct Renderer {
     pub target: RenderTarget,
+    /// Number of threads rasterizing tiles, 1 renders everything on the calling thread.
+    pub thread_count: usize,
+
+    /// Post-transform vertex cache, `vertex_slots` maps a vertex index to its entry in `transformed`.
+    vertex_slots: Vec<u32>,
+    transformed: Vec<(ClipVertex, u32)>,
+    /// `0, 1, 2, ...` for non-indexed draws, grown as needed.
+    sequential_indices: Vec<u32>,
+    /// Scratch space for clipping.
+    polygon: Vec<ClipVertex>,
+    clip_scratch: Vec<ClipVertex>,
+    primitives: Vec<RasterPrimitive>,
+    bins: TileBins,
+    /// Started on the first draw with more than one row of tiles to rasterize.
+    workers: WorkerPool,
 }
 
 impl Renderer {
+    #[allow(dead_code)]
     pub fn new(width: i32, height: i32) -> Self {
+        Self::with_target(RenderTarget::new(width, height))
+    }
+
+    pub fn new_multisampled(width: i32, height: i32, samples: SampleCount) -> Self {
+        Self::with_target(RenderTarget::new_multisampled(width, height, samples))
+    }
+
+    fn with_target(target: RenderTarget) -> Self {
+        let (width, height) = (target.width, target.height);
         Self {
-            target: RenderTarget::new(width, height),
+            target: target,
+            thread_count: std::thread::available_parallelism().map_or(1, |n| n.get()),
+
+            vertex_slots: Vec::new(),
+            transformed: Vec::new(),
+            sequential_indices: Vec::new(),
+            polygon: Vec::with_capacity(9),
+            clip_scratch: Vec::with_capacity(9),
+            primitives: Vec::new(),
+            bins: TileBins::new(width, height),
+            workers: WorkerPool::new(0),
         }
     }
 
     pub fn clear_color(self: &mut Self, color: Vec4) {
         self.target.color_buffer.iter_mut().for_each(|x| *x = color);
+        self.target.sample_color_buffer.iter_mut().for_each(|x| *x = color);
+    }
+
+    pub fn clear_depth(self: &mut Self, depth: f32) {
+        self.target.clear_depth(depth);
+    }
+
+    pub fn clear_stencil(self: &mut Self, stencil: u8) {
+        self.target.stencil_buffer.iter_mut().for_each(|x| *x = stencil);
+    }
+
+    /// Draws the vertices in the order given by the index buffer, assembled into primitives by the pipeline topology.
+    pub fn draw_indexed<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>, vert_buf: &[Vertex], index_buf: &[u32], uniforms: &Uniforms) {
+        self.draw_instances(pipeline, vert_buf, index_buf, None, uniforms);
+    }
+
+    /// Draws the indexed geometry once for every instance. The instances share the clipping setup and are
+    /// rasterized together, in instance order, which is a lot cheaper than a draw per instance.
+    pub fn draw_instanced<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>, vert_buf: &[Vertex], index_buf: &[u32], instances: &[Instance], uniforms: &Uniforms) {
+        if instances.is_empty() {
+            return;
+        }
+
+        self.draw_instances(pipeline, vert_buf, index_buf, Some(instances), uniforms);
+    }
+
+    /// Draws the vertices in the order they are in the buffer, assembled into primitives by the pipeline topology.
+    pub fn draw<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>, vert_buf: &[Vertex], uniforms: &Uniforms) {
+        let mut indices = std::mem::take(&mut self.sequential_indices);
+        if indices.len() < vert_buf.len() {
+            indices.extend(indices.len() as u32..vert_buf.len() as u32);
+        }
+
+        self.draw_indexed(pipeline, vert_buf, &indices[..vert_buf.len()], uniforms);
+        self.sequential_indices = indices;
     }
 
-    pub fn draw_triangles(&mut self, vert_buf: &Vec<Vertex>, index_buf: &Vec<u32>, in_model: ModelTransform, in_camera: CameraTransform, in_wts: WorldToScreenTransform) {
-        if index_buf.len() == 0 {
+    fn draw_instances<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>, vert_buf: &[Vertex], index_buf: &[u32], instances: Option<&[Instance]>, uniforms: &Uniforms) {
+        if index_buf.is_empty() {
             return;
         }
 
-        let mut rng = rand::rngs::StdRng::from_seed([128;32]);
+        self.primitives.clear();
 
-        let mut model = in_model.clone();
-        let mut camera = in_camera.clone();
-        let mut wts = in_wts.clone();
+        let target_size = IVec2::new(self.target.width, self.target.height);
+        let viewport = pipeline.viewport.unwrap_or(Viewport::new(0.0, 0.0, target_size.x as f32, target_size.y as f32));
+        let (clip_min, clip_max) = pixel_bounds(&viewport, pipeline.scissor.as_ref(), target_size);
+        if clip_min.x > clip_max.x || clip_min.y > clip_max.y {
+            return;
+        }
+
+        match instances {
+            Some(instances) => {
+                for (i, instance) in instances.iter().enumerate() {
+                    self.transform_vertices(pipeline, vert_buf, index_buf, uniforms, i as u32, Some(instance));
+                    self.assemble_primitives(pipeline, index_buf, &viewport, clip_min, clip_max);
+                }
+            }
+            None => {
+                self.transform_vertices(pipeline, vert_buf, index_buf, uniforms, 0, None);
+                self.assemble_primitives(pipeline, index_buf, &viewport, clip_min, clip_max);
+            }
+        }
+
+        self.rasterize(pipeline);
+    }
+
+    /// Runs the vertex shader over every vertex referenced by the index buffer, exactly once each.
+    fn transform_vertices<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>, vert_buf: &[Vertex], index_buf: &[u32], uniforms: &Uniforms, instance_index: u32, instance: Option<&Instance>) {
+        self.vertex_slots.clear();
+        self.vertex_slots.resize(vert_buf.len(), UNTRANSFORMED);
+        self.transformed.clear();
+        for &index in index_buf {
+            let slot = &mut self.vertex_slots[index as usize];
+            if *slot != UNTRANSFORMED {
+                continue;
+            }
+            *slot = self.transformed.len() as u32;
+
+            let input = VertexInput {
+                vertex: &vert_buf[index as usize],
+                uniforms: uniforms,
+                instance_index: instance_index,
+                instance: instance,
+            };
+            let mut varyings = Varyings::ZERO;
+            let position = pipeline.vertex_shader.shade(&input, &mut varyings);
+
+            let clip = ClipVertex::new(position, varyings);
+            let code = outcode(clip.position);
+            self.transformed.push((clip, code));
+        }
+    }
+
+    /// Groups the transformed vertices into primitives by the pipeline topology and queues them for rasterization.
+    fn assemble_primitives<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>, index_buf: &[u32], viewport: &Viewport, clip_min: IVec2, clip_max: IVec2) {
+        match pipeline.topology {
+            PrimitiveTopology::TriangleList => {
+                for face in index_buf.chunks_exact(3) {
+                    self.assemble_triangle([face[0], face[1], face[2]], pipeline, viewport, clip_min, clip_max);
+                }
+            }
+            PrimitiveTopology::TriangleStrip => {
+                for (i, face) in index_buf.windows(3).enumerate() {
+                    // Every other triangle of a strip winds the other way, swap two vertices to keep them consistent
+                    let face = if i % 2 == 0 { [face[0], face[1], face[2]] } else { [face[1], face[0], face[2]] };
+                    self.assemble_triangle(face, pipeline, viewport, clip_min, clip_max);
+                }
+            }
+            PrimitiveTopology::TriangleFan => {
+                for edge in index_buf[1..].windows(2) {
+                    self.assemble_triangle([index_buf[0], edge[0], edge[1]], pipeline, viewport, clip_min, clip_max);
+                }
+            }
+            PrimitiveTopology::LineList => {
+                for line in index_buf.chunks_exact(2) {
+                    self.assemble_line([line[0], line[1]], 0.0, pipeline, viewport, clip_min, clip_max);
+                }
+            }
+            PrimitiveTopology::LineStrip => {
+                for line in index_buf.windows(2) {
+                    self.assemble_line([line[0], line[1]], 0.0, pipeline, viewport, clip_min, clip_max);
+                }
+            }
+            PrimitiveTopology::PointList => {
+                for &index in index_buf {
+                    self.assemble_point(index, 0.0, pipeline, viewport, clip_min, clip_max);
+                }
+            }
+        }
+    }
 
-        model.calculate_transform();
-        camera.calculate_transform();
-        wts.calculate_transform();
-        
-        let triangle_count = index_buf.len() / 3;
-        for i in 0..triangle_count {
-            let face_idx = i*3;
-            let i1 = index_buf[face_idx+0];
-            let i2 = index_buf[face_idx+1];
-            let i3 = index_buf[face_idx+2];
+    #[inline]
+    fn transformed_vertex(&self, index: u32) -> &(ClipVertex, u32) {
+        &self.transformed[self.vertex_slots[index as usize] as usize]
+    }
 
-            let mut v1 = vert_buf[i1 as usize].clone();
-            let mut v2 = vert_buf[i2 as usize].clone();
-            let mut v3 = vert_buf[i3 as usize].clone();
+    /// Clips and culls a triangle, then queues it according to the polygon mode.
+    fn assemble_triangle<V: VertexShader, F: FragmentShader>(&mut self, face: [u32; 3], pipeline: &PipelineState<V, F>, viewport: &Viewport, clip_min: IVec2, clip_max: IVec2) {
+        let mut codes = [0u32; 3];
 
-            model.apply_transform(&mut v1.position);
-            model.apply_transform(&mut v2.position);
-            model.apply_transform(&mut v3.position);
+        self.polygon.clear();
+        for corner in 0..3 {
+            let (clip, code) = &self.transformed[self.vertex_slots[face[corner] as usize] as usize];
+            self.polygon.push(clip.clone());
+            codes[corner] = *code;
+        }
 
-            camera.apply_transform(&mut v1.position);
-            camera.apply_transform(&mut v2.position);
-            camera.apply_transform(&mut v3.position);
+        if codes[0] & codes[1] & codes[2] != 0 {
+            // Every vertex is outside the same plane
+            return;
+        }
+
+        let straddled = codes[0] | codes[1] | codes[2];
+        if straddled != 0 {
+            clip_polygon(&mut self.polygon, &mut self.clip_scratch, straddled);
+        }
+
+        if self.polygon.len() < 3 {
+            return;
+        }
 
-            wts.apply_transform(&mut v1.position);
-            wts.apply_transform(&mut v2.position);
-            wts.apply_transform(&mut v3.position);
+        let mut screen: Vec<ScreenVertex> = self.polygon.iter().map(|v| ScreenVertex::new(v, viewport)).collect();
 
-            let p1 = IVec2::new(v1.position.x as i32, v1.position.y as i32);
-            let p2 = IVec2::new(v2.position.x as i32, v2.position.y as i32);
-            let p3 = IVec2::new(v3.position.x as i32, v3.position.y as i32);
+        // Clipping keeps the winding, so the whole polygon faces the same way as the triangle
+        let mut area = 0.0;
+        for fan in 1..screen.len()-1 {
+            area += edge_function(screen[0].position.xy(), screen[fan].position.xy(), screen[fan+1].position.xy());
+        }
+        if area == 0.0 {
+            return;
+        }
 
-            let bound_start_x = clamp(p1.x.min(p2.x.min(p3.x)), 0, self.target.width-1);
-            let bound_start_y = clamp(p1.y.min(p2.y.min(p3.y)), 0, self.target.height-1);
-            let bound_end_x = clamp(p1.x.max(p2.x.max(p3.x)), 0, self.target.width-1);
-            let bound_end_y = clamp(p1.y.max(p2.y.max(p3.y)), 0, self.target.height-1);
+        let is_front = match pipeline.front_face {
+            FrontFace::CounterClockwise => area > 0.0,
+            FrontFace::Clockwise => area < 0.0,
+        };
+        let culled = match pipeline.cull_mode {
+            CullMode::None => false,
+            CullMode::Front => is_front,
+            CullMode::Back => !is_front,
+        };
+        if culled {
+            return;
+        }
 
-            let p1f32 = p1.as_f32();
-            let p2f32 = p2.as_f32();
-            let p3f32 = p3.as_f32();
+        // Also applies to the edges and vertices drawn for the triangle by the polygon mode, but not to lines and points
+        let mut depth_bias = 0.0;
+        if !pipeline.depth_bias.is_none() {
+            let max_depth = screen.iter().fold(0.0f32, |max, v| max.max(v.position.z.abs()));
+            depth_bias = pipeline.depth_bias.offset(max_depth, depth_slope(&screen));
+            screen.iter_mut().for_each(|v| v.position.z += depth_bias);
+        }
 
-            let mut col = Vec4::rand_01(&mut rng);
-            col.w = 1.0;
+        match pipeline.polygon_mode {
+            PolygonMode::Fill => {
+                for fan in 1..screen.len()-1 {
+                    // The rasterizer expects counter-clockwise triangles
+                    let verts = if area > 0.0 {
+                        [screen[0].clone(), screen[fan].clone(), screen[fan+1].clone()]
+                    } else {
+                        [screen[0].clone(), screen[fan+1].clone(), screen[fan].clone()]
+                    };
 
-            for y in bound_start_y..=bound_end_y {
-                for x in bound_start_x..=bound_end_x {
-                    if is_point_in_triangle(Vec2::new(x as f32, y as f32), p1f32, p2f32, p3f32) {
-                        self.target.color_buffer[(y * self.target.width + x) as usize] = col;
+                    self.primitives.extend(triangle_primitive(verts, clip_min, clip_max));
+                }
+            }
+            PolygonMode::Line => {
+                // The edges of the original triangle, edges added by clipping aren't part of the model
+                for edge in 0..3 {
+                    self.assemble_line([face[edge], face[(edge + 1) % 3]], depth_bias, pipeline, viewport, clip_min, clip_max);
+                }
+            }
+            PolygonMode::Point => {
+                for index in face {
+                    self.assemble_point(index, depth_bias, pipeline, viewport, clip_min, clip_max);
+                }
+            }
+        }
+    }
+
+    fn assemble_line<V: VertexShader, F: FragmentShader>(&mut self, line: [u32; 2], depth_bias: f32, pipeline: &PipelineState<V, F>, viewport: &Viewport, clip_min: IVec2, clip_max: IVec2) {
+        let (mut a, code_a) = self.transformed_vertex(line[0]).clone();
+        let (mut b, code_b) = self.transformed_vertex(line[1]).clone();
+
+        if code_a & code_b != 0 || !clip_line(&mut a, &mut b, code_a | code_b) {
+            return;
+        }
+
+        let mut verts = [ScreenVertex::new(&a, viewport), ScreenVertex::new(&b, viewport)];
+        verts.iter_mut().for_each(|v| v.position.z += depth_bias);
+        self.primitives.extend(line_primitive(verts, pipeline.line_width, clip_min, clip_max));
+    }
+
+    /// Points are dropped as a whole once their centre leaves the view volume.
+    fn assemble_point<V: VertexShader, F: FragmentShader>(&mut self, index: u32, depth_bias: f32, pipeline: &PipelineState<V, F>, viewport: &Viewport, clip_min: IVec2, clip_max: IVec2) {
+        let (clip, code) = self.transformed_vertex(index);
+        if *code != 0 {
+            return;
+        }
+
+        let mut vert = ScreenVertex::new(clip, viewport);
+        vert.position.z += depth_bias;
+        self.primitives.extend(point_primitive(vert, pipeline.point_size, clip_min, clip_max));
+    }
+
+    /// Bins the pending primitives into screen tiles and rasterizes each row of tiles on a worker thread.
+    /// Every pixel is only ever touched by the one thread owning its row, and always in submission
+    /// order, so the result is the same no matter how many threads are used.
+    fn rasterize<V: VertexShader, F: FragmentShader>(&mut self, pipeline: &PipelineState<V, F>) {
+        if self.primitives.is_empty() {
+            return;
+        }
+
+        self.bins.clear();
+        for (i, primitive) in self.primitives.iter().enumerate() {
+            let (bound_min, bound_max) = primitive.bounds();
+            self.bins.insert(i as u32, bound_min, bound_max);
+        }
+
+        let bins = &self.bins;
+        let primitives = &self.primitives;
+
+        // Rows of tiles nothing was binned into have nothing to do
+        let rows: Vec<TileRow> = self.target.tile_rows().into_iter()
+            .filter(|row| bins.row_has_work(row.y / TILE_SIZE))
+            .collect();
+        if self.thread_count <= 1 || rows.len() <= 1 {
+            for mut row in rows {
+                rasterize_tile_row(&mut row, bins, primitives, pipeline);
+            }
+            return;
+        }
+
+        // The calling thread works through the rows as well
+        if self.workers.workers() != self.thread_count - 1 {
+            self.workers = WorkerPool::new(self.thread_count - 1);
+        }
+
+        let jobs = Mutex::new(rows.into_iter());
+        self.workers.run(&|| loop {
+            let job = jobs.lock().unwrap().next();
+            let Some(mut row) = job else {
+                break;
+            };
+            rasterize_tile_row(&mut row, bins, primitives, pipeline);
+        });
+    }
+}
+
+/// Largest change in depth per pixel across the plane of a polygon.
+fn depth_slope(polygon: &[ScreenVertex]) -> f32 {
+    let [a, b, c] = [polygon[0].position, polygon[1].position, polygon[2].position];
+    let area = edge_function(a.xy(), b.xy(), c.xy());
+    if area == 0.0 {
+        return 0.0;
+    }
+
+    let ddx = ((b.z - a.z) * (c.y - a.y) - (c.z - a.z) * (b.y - a.y)) / area;
+    let ddy = ((c.z - a.z) * (b.x - a.x) - (b.z - a.z) * (c.x - a.x)) / area;
+
+    ddx.abs().max(ddy.abs())
+}
+
+/// Every interpolated depth is clamped to the viewport's depth range, as in D3D. Only depth bias can push it outside,
+/// clipping already keeps everything else within.
+fn depth_range<V: VertexShader, F: FragmentShader>(pipeline: &PipelineState<V, F>) -> (f32, f32) {
+    pipeline.viewport.map_or((0.0, 1.0), |viewport| (viewport.min_depth.min(viewport.max_depth), viewport.min_depth.max(viewport.max_depth)))
+}
+
+/// Inclusive pixel rectangle a draw may touch, the viewport and scissor rectangle clamped to the render target.
+fn pixel_bounds(viewport: &Viewport, scissor: Option<&ScissorRect>, target_size: IVec2) -> (IVec2, IVec2) {
+    let mut min = IVec2::new(viewport.x.max(0.0) as i32, viewport.y.max(0.0) as i32);
+    let mut max = IVec2::new(
+        ((viewport.x + viewport.width).ceil() as i32).min(target_size.x) - 1,
+        ((viewport.y + viewport.height).ceil() as i32).min(target_size.y) - 1,
+    );
+
+    if let Some(scissor) = scissor {
+        min = IVec2::new(min.x.max(scissor.x), min.y.max(scissor.y));
+        max = IVec2::new(max.x.min(scissor.x + scissor.width - 1), max.y.min(scissor.y + scissor.height - 1));
+    }
+
+    (min, max)
+}
+
+/// Snaps a counter-clockwise triangle and sets it up for rasterization, `None` if it covers no pixels.
+fn triangle_primitive(verts: [ScreenVertex; 3], clip_min: IVec2, clip_max: IVec2) -> Option<RasterPrimitive> {
+    let p1 = FixedPoint::snap(verts[0].position.xy());
+    let p2 = FixedPoint::snap(verts[1].position.xy());
+    let p3 = FixedPoint::snap(verts[2].position.xy());
+
+    let mut setup = TriangleSetup::new(p1, p2, p3)?;
+    setup.bound_min = IVec2::new(setup.bound_min.x.max(clip_min.x), setup.bound_min.y.max(clip_min.y));
+    setup.bound_max = IVec2::new(setup.bound_max.x.min(clip_max.x), setup.bound_max.y.min(clip_max.y));
+    if setup.bound_min.x > setup.bound_max.x || setup.bound_min.y > setup.bound_max.y {
+        return None;
+    }
+
+    Some(RasterPrimitive::Triangle(RasterTriangle { block: BlockSetup::new(&setup), setup: setup, verts: verts }))
+}
+
+fn line_primitive(verts: [ScreenVertex; 2], width: f32, clip_min: IVec2, clip_max: IVec2) -> Option<RasterPrimitive> {
+    let [a, b] = [verts[0].position.xy(), verts[1].position.xy()];
+    let extent = (width * 0.5).ceil() + 1.0;
+
+    let bound_min = IVec2::new(((a.x.min(b.x) - extent).floor() as i32).max(clip_min.x), ((a.y.min(b.y) - extent).floor() as i32).max(clip_min.y));
+    let bound_max = IVec2::new(((a.x.max(b.x) + extent).ceil() as i32).min(clip_max.x), ((a.y.max(b.y) + extent).ceil() as i32).min(clip_max.y));
+    if width <= 0.0 || bound_min.x > bound_max.x || bound_min.y > bound_max.y {
+        return None;
+    }
+
+    Some(RasterPrimitive::Line(RasterLine { verts: verts, bound_min: bound_min, bound_max: bound_max }))
+}
+
+fn point_primitive(vert: ScreenVertex, size: f32, clip_min: IVec2, clip_max: IVec2) -> Option<RasterPrimitive> {
+    let center = vert.position.xy();
+    let extent = size * 0.5;
+
+    let bound_min = IVec2::new(((center.x - extent).floor() as i32).max(clip_min.x), ((center.y - extent).floor() as i32).max(clip_min.y));
+    let bound_max = IVec2::new(((center.x + extent).ceil() as i32).min(clip_max.x), ((center.y + extent).ceil() as i32).min(clip_max.y));
+    if size <= 0.0 || bound_min.x > bound_max.x || bound_min.y > bound_max.y {
+        return None;
+    }
+
+    Some(RasterPrimitive::Point(RasterPoint { vert: vert, bound_min: bound_min, bound_max: bound_max }))
+}
+
+fn rasterize_tile_row<V: VertexShader, F: FragmentShader>(row: &mut TileRow, bins: &TileBins, primitives: &[RasterPrimitive], pipeline: &PipelineState<V, F>) {
+    let tile_y = row.y / TILE_SIZE;
+    for tile_x in 0..bins.tiles_x {
+        let tile_min = IVec2::new(tile_x * TILE_SIZE, row.y);
+        let tile_max = IVec2::new(((tile_x + 1) * TILE_SIZE).min(row.width) - 1, row.y + row.height - 1);
+
+        for &primitive in bins.get(tile_x, tile_y) {
+            match &primitives[primitive as usize] {
+                RasterPrimitive::Triangle(tri) => rasterize_triangle(row, tile_min, tile_max, tri, pipeline),
+                RasterPrimitive::Line(line) => rasterize_line(row, tile_min, tile_max, line, pipeline),
+                RasterPrimitive::Point(point) => rasterize_point(row, tile_min, tile_max, point, pipeline),
+            }
+        }
+    }
+}
+
+/// Widens depth ranges estimated for coarse depth rejection so they still hold every interpolated
+/// depth, which can be a few ulps off. Without it redrawing a mesh with `LessEqual` or `Equal` drops blocks.
+const DEPTH_SLACK: f32 = 1e-6;
+
+/// Rasterizes the part of a triangle that falls within the given tile, one block of pixels at a time.
+fn rasterize_triangle<V: VertexShader, F: FragmentShader>(row: &mut TileRow, tile_min: IVec2, tile_max: IVec2, tri: &RasterTriangle, pipeline: &PipelineState<V, F>) {
+    let setup = &tri.setup;
+
+    let bound_min = IVec2::new(setup.bound_min.x.max(tile_min.x), setup.bound_min.y.max(tile_min.y));
+    let bound_max = IVec2::new(setup.bound_max.x.min(tile_max.x), setup.bound_max.y.min(tile_max.y));
+
+    if bound_min.x > bound_max.x || bound_min.y > bound_max.y {
+        return;
+    }
+
+    // Tiles are a multiple of the block size, so aligned blocks never straddle two tiles
+    let block_start = IVec2::new(bound_min.x & !(BLOCK_SIZE - 1), bound_min.y & !(BLOCK_SIZE - 1));
+
+    let [edge1, edge2, edge3] = setup.edges;
+    let z = Vec3::new(tri.verts[0].position.z, tri.verts[1].position.z, tri.verts[2].position.z);
+    let depth_ddx = setup.bary_ddx.dot(z);
+    let depth_ddy = setup.bary_ddy.dot(z);
+
+    // Every covered sample lies within the triangle, so its depth is within that of the vertices, give or
+    // take the rounding of interpolating it. Clamping to the depth range only narrows it further.
+    let z_min = z.x.min(z.y.min(z.z)) - DEPTH_SLACK;
+    let z_max = z.x.max(z.y.max(z.z)) + DEPTH_SLACK;
+    let tile_x = tile_min.x / TILE_SIZE;
+    let coarse_depth = coarse_depth_test(pipeline);
+    let depth_range = depth_range(pipeline);
+    if coarse_depth && row.tile_depth[tile_x as usize].occludes(pipeline.depth_func, z_min, z_max) {
+        return;
+    }
+
+    // Depth range over a block and its samples, relative to the depth at the first pixel centre
+    let span = (BLOCK_SIZE - 1) as f32 + 0.5;
+    let block_depth_min = (-0.5 * depth_ddx).min(span * depth_ddx) + (-0.5 * depth_ddy).min(span * depth_ddy);
+    let block_depth_max = (-0.5 * depth_ddx).max(span * depth_ddx) + (-0.5 * depth_ddy).max(span * depth_ddy);
+    let mut tile_written = false;
+
+    // Offsets of the edge values and depth from the pixel centre to each sample. Sample positions are in
+    // 1/16th of a pixel and the edge steps are whole multiples of SUBPIXEL_ONE, so the edge offsets are exact
+    let pattern = row.sample_pattern;
+    let multisampled = pattern.len() > 1;
+    let mut sample_edges = [[0i64; 3]; MAX_SAMPLES];
+    let mut sample_depths = [0.0f32; MAX_SAMPLES];
+    for (s, offset) in pattern.iter().enumerate() {
+        for (i, edge) in setup.edges.iter().enumerate() {
+            sample_edges[s][i] = (offset.x as i64 * edge.step_x + offset.y as i64 * edge.step_y) / 16;
+        }
+        sample_depths[s] = (offset.x as f32 * depth_ddx + offset.y as f32 * depth_ddy) / 16.0;
+    }
+
+    let mut depth = [0.0f32; BLOCK_PIXELS];
+    let mut bary = [[0.0f32; BLOCK_PIXELS]; 3];
+    let mut sample_masks = [0u16; MAX_SAMPLES];
+
+    let mut row_e = setup.evaluate(block_start.x, block_start.y);
+    for block_y in (block_start.y..=bound_max.y).step_by(BLOCK_SIZE as usize) {
+        let mut e = row_e;
+        for block_x in (block_start.x..=bound_max.x).step_by(BLOCK_SIZE as usize) {
+            let block = IVec2::new(block_x, block_y);
+            let mut mask = 0;
+            if multisampled {
+                for (s, offsets) in sample_edges[..pattern.len()].iter().enumerate() {
+                    sample_masks[s] = tri.block.coverage([e[0] + offsets[0], e[1] + offsets[1], e[2] + offsets[2]]);
+                    mask |= sample_masks[s];
+                }
+            } else {
+                mask = tri.block.coverage(e);
+            }
+            if mask != 0 {
+                mask &= rect_mask(block, bound_min, bound_max);
+            }
+
+            let origin = if mask != 0 { setup.barycentrics(e) } else { Vec3::ZERO };
+            if mask != 0 && coarse_depth {
+                let depth_origin = origin.dot(z);
+                let min = (depth_origin + block_depth_min - DEPTH_SLACK).max(z_min);
+                let max = (depth_origin + block_depth_max + DEPTH_SLACK).min(z_max);
+                if row.block_depth[row.block_index(block_x, block_y)].occludes(pipeline.depth_func, min, max) {
+                    mask = 0;
+                }
+            }
+
+            if mask != 0 {
+                let mut written = false;
+                eval_plane(origin.x, setup.bary_ddx.x, setup.bary_ddy.x, &mut bary[0]);
+                eval_plane(origin.y, setup.bary_ddx.y, setup.bary_ddy.y, &mut bary[1]);
+                eval_plane(origin.z, setup.bary_ddx.z, setup.bary_ddy.z, &mut bary[2]);
+                eval_plane(origin.dot(z), depth_ddx, depth_ddy, &mut depth);
+
+                while mask != 0 {
+                    let i = mask.trailing_zeros() as usize;
+                    mask &= mask - 1;
+
+                    let x = block_x + i as i32 % BLOCK_SIZE;
+                    let y = block_y + i as i32 / BLOCK_SIZE;
+                    let mut samples = SampleCoverage { mask: 1, depth: depth[i], depth_offsets: &sample_depths, depth_range: depth_range };
+                    if multisampled {
+                        samples.mask = 0;
+                        for (s, sample_mask) in sample_masks[..pattern.len()].iter().enumerate() {
+                            samples.mask |= ((sample_mask >> i) as u32 & 1) << s;
+                        }
                     }
+
+                    // Shaded once at the pixel centre, even if only some of the samples are covered
+                    let weights = Vec3::new(bary[0][i], bary[1][i], bary[2][i]);
+                    written |= shade_fragment(row, x, y, &samples, 1.0, || {
+                        let count = pipeline.vertex_shader.varying_count().min(MAX_VARYINGS);
+                        let varyings = triangle_varyings(&tri.verts, weights, count);
+
+                        // Finite differences to the neighbouring pixels, the barycentrics are linear in screen space
+                        let (ddx, ddy) = if pipeline.fragment_shader.needs_derivatives() {
+                            let right = triangle_varyings(&tri.verts, weights + setup.bary_ddx, count);
+                            let up = triangle_varyings(&tri.verts, weights + setup.bary_ddy, count);
+                            (Varyings::difference(&varyings, &right, count), Varyings::difference(&varyings, &up, count))
+                        } else {
+                            (Varyings::ZERO, Varyings::ZERO)
+                        };
+
+                        Fragment {
+                            position: Vec3::new(x as f32 + 0.5, y as f32 + 0.5, samples.centre_depth()),
+                            varyings: varyings,
+                            point_coord: Vec2::ZERO,
+                            ddx: ddx,
+                            ddy: ddy,
+                        }
+                    }, pipeline);
+                }
+
+                if written {
+                    row.update_block_depth(block_x, block_y);
+                    tile_written = true;
                 }
             }
+
+            e[0] += edge1.step_x * BLOCK_SIZE as i64;
+            e[1] += edge2.step_x * BLOCK_SIZE as i64;
+            e[2] += edge3.step_x * BLOCK_SIZE as i64;
+        }
+
+        row_e[0] += edge1.step_y * BLOCK_SIZE as i64;
+        row_e[1] += edge2.step_y * BLOCK_SIZE as i64;
+        row_e[2] += edge3.step_y * BLOCK_SIZE as i64;
+    }
+
+    if tile_written {
+        row.update_tile_depth(tile_x);
+    }
+}
+
+/// Rasterizes the part of a line that falls within the given tile.
+fn rasterize_line<V: VertexShader, F: FragmentShader>(row: &mut TileRow, tile_min: IVec2, tile_max: IVec2, line: &RasterLine, pipeline: &PipelineState<V, F>) {
+    let bound_min = IVec2::new(line.bound_min.x.max(tile_min.x), line.bound_min.y.max(tile_min.y));
+    let bound_max = IVec2::new(line.bound_max.x.min(tile_max.x), line.bound_max.y.min(tile_max.y));
+
+    if bound_min.x > bound_max.x || bound_min.y > bound_max.y {
+        return;
+    }
+
+    let [a, b] = &line.verts;
+    let depth_range = depth_range(pipeline);
+    let mut plot = |x: i32, y: i32, t: f32, coverage: f32| {
+        let samples = SampleCoverage::all(row.sample_pattern.len(), lerp(a.position.z, b.position.z, t), depth_range);
+        let depth = samples.centre_depth();
+        let written = shade_fragment(row, x, y, &samples, coverage, || Fragment {
+            position: Vec3::new(x as f32 + 0.5, y as f32 + 0.5, depth),
+            varyings: line_varyings(t, &line.verts),
+            point_coord: Vec2::ZERO,
+            ddx: Varyings::ZERO,
+            ddy: Varyings::ZERO,
+        }, pipeline);
+        if written {
+            row.include_depth(x, y, depth);
+        }
+    };
+
+    match pipeline.line_mode {
+        LineMode::Bresenham => {
+            let from = IVec2::new(a.position.x.floor() as i32, a.position.y.floor() as i32);
+            let to = IVec2::new(b.position.x.floor() as i32, b.position.y.floor() as i32);
+            let width = (pipeline.line_width.round() as i32).max(1);
+            bresenham(from, to, width, bound_min, bound_max, |x, y, t| plot(x, y, t, 1.0));
+        }
+        LineMode::XiaolinWu => {
+            xiaolin_wu(a.position.xy(), b.position.xy(), pipeline.line_width, bound_min, bound_max, plot);
+        }
+    }
+}
+
+/// Rasterizes the part of a point sprite that falls within the given tile.
+fn rasterize_point<V: VertexShader, F: FragmentShader>(row: &mut TileRow, tile_min: IVec2, tile_max: IVec2, point: &RasterPoint, pipeline: &PipelineState<V, F>) {
+    let bound_min = IVec2::new(point.bound_min.x.max(tile_min.x), point.bound_min.y.max(tile_min.y));
+    let bound_max = IVec2::new(point.bound_max.x.min(tile_max.x), point.bound_max.y.min(tile_max.y));
+
+    if bound_min.x > bound_max.x || bound_min.y > bound_max.y {
+        return;
+    }
+
+    let vert = &point.vert;
+    let samples = SampleCoverage::all(row.sample_pattern.len(), vert.position.z, depth_range(pipeline));
+    let depth = samples.centre_depth();
+    point_sprite(vert.position.xy(), pipeline.point_size, bound_min, bound_max, |x, y, point_coord| {
+        let written = shade_fragment(row, x, y, &samples, 1.0, || Fragment {
+            position: Vec3::new(x as f32 + 0.5, y as f32 + 0.5, depth),
+            varyings: vert.varyings,
+            point_coord: point_coord,
+            ddx: Varyings::ZERO,
+            ddy: Varyings::ZERO,
+        }, pipeline);
+        if written {
+            row.include_depth(x, y, depth);
+        }
+    });
+}
+
+/// Perspective correct varyings at the given screen space barycentrics.
+#[inline]
+fn triangle_varyings([v1, v2, v3]: &[ScreenVertex; 3], bary: Vec3, count: usize) -> Varyings {
+    // Screen space weights are linear in 1/w, so weight each varying by it and renormalize
+    let mut weights = bary * Vec3::new(v1.inv_w, v2.inv_w, v3.inv_w);
+    weights = weights / (weights.x + weights.y + weights.z);
+
+    Varyings::interpolate(&v1.varyings, &v2.varyings, &v3.varyings, weights, count)
+}
+
+/// Perspective correct varyings at `t` along a line in screen space.
+fn line_varyings(t: f32, [a, b]: &[ScreenVertex; 2]) -> Varyings {
+    let weight_a = (1.0 - t) * a.inv_w;
+    let weight_b = t * b.inv_w;
+
+    Varyings::lerp(&a.varyings, &b.varyings, weight_b / (weight_a + weight_b))
+}
+
+/// Runs the stencil and depth tests on every covered sample, then shades the fragment made by `fragment` once
+/// and writes the results to the samples that passed. The colour's alpha is scaled by `coverage` before blending.
+/// Stencil operations for samples that failed are only applied if the shader doesn't discard the fragment.
+/// Returns whether any depth was written.
+#[inline]
+fn shade_fragment<V: VertexShader, F: FragmentShader>(row: &mut TileRow, x: i32, y: i32, samples: &SampleCoverage, coverage: f32, fragment: impl FnOnce() -> Fragment, pipeline: &PipelineState<V, F>) -> bool {
+    let first = row.index(x, y) * row.sample_pattern.len();
+    let stencil = &pipeline.stencil;
+
+    // Stencil writes wait until the shader has run, a discarded fragment leaves every buffer untouched
+    let mut passed = 0u32;
+    let mut stencil_failed = 0u32;
+    let mut depth_failed = 0u32;
+    let mut mask = samples.mask;
+    while mask != 0 {
+        let s = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+
+        let idx = first + s;
+        if stencil.enabled && !stencil.test(row.stencil_buffer[idx]) {
+            stencil_failed |= 1 << s;
+        }
+        else if !pipeline.depth_func.test(samples.sample_depth(s), row.depth_buffer[idx]) {
+            depth_failed |= 1 << s;
+        }
+        else {
+            passed |= 1 << s;
+        }
+    }
+
+    let writes_failed = stencil.enabled
+        && ((stencil_failed != 0 && stencil.fail_op != StencilOp::Keep) || (depth_failed != 0 && stencil.depth_fail_op != StencilOp::Keep));
+    if passed == 0 && !writes_failed {
+        return false;
+    }
+
+    let Some(mut color) = pipeline.fragment_shader.shade(&fragment()) else {
+        return false;
+    };
+
+    if writes_failed {
+        for (mut failed, op) in [(stencil_failed, stencil.fail_op), (depth_failed, stencil.depth_fail_op)] {
+            while failed != 0 {
+                let idx = first + failed.trailing_zeros() as usize;
+                failed &= failed - 1;
+                row.stencil_buffer[idx] = stencil.update(row.stencil_buffer[idx], op);
+            }
         }
     }
+
+    if passed == 0 {
+        return false;
+    }
+    color.w *= coverage;
+
+    while passed != 0 {
+        let s = passed.trailing_zeros() as usize;
+        passed &= passed - 1;
+
+        let idx = first + s;
+        if stencil.enabled {
+            row.stencil_buffer[idx] = stencil.update(row.stencil_buffer[idx], stencil.pass_op);
+        }
+        if pipeline.depth_write {
+            row.depth_buffer[idx] = samples.sample_depth(s);
+        }
+        row.color_buffer[idx] = pipeline.blend.blend(color, row.color_buffer[idx]);
+    }
+
+    pipeline.depth_write
+}
+
+/// Whether geometry can be rejected by the coarse depth bounds. Not when failing the depth test
+/// has to update the stencil buffer, since that needs every sample to be tested.
+fn coarse_depth_test<V: VertexShader, F: FragmentShader>(pipeline: &PipelineState<V, F>) -> bool {
+    let stencil = &pipeline.stencil;
+
+    !stencil.enabled || (stencil.fail_op == StencilOp::Keep && stencil.depth_fail_op == StencilOp::Keep)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::*;
+
+    /// Takes vertex positions as normalized device coordinates with depth in z, and passes on the texture coordinates.
+    struct NdcVertexShader;
+
+    impl VertexShader for NdcVertexShader {
+        fn shade(&self, input: &VertexInput, out: &mut Varyings) -> Vec4 {
+            out.set_vec2(0, input.vertex.tex_coords);
+
+            input.vertex.position.v4()
+        }
+
+        fn varying_count(&self) -> usize {
+            2
+        }
+    }
+
+    /// Writes a single colour, or discards every fragment when given `None`.
+    struct SolidFragmentShader(Option<Vec4>);
+
+    impl FragmentShader for SolidFragmentShader {
+        fn shade(&self, _fragment: &Fragment) -> Option<Vec4> {
+            self.0
+        }
+    }
+
+    fn vertex(x: f32, y: f32, z: f32) -> Vertex {
+        Vertex::new(Vec3::new(x, y, z), Vec3::ZERO, Vec2::new(x, y))
+    }
+
+    fn uniforms() -> Uniforms {
+        Uniforms::new(
+            ModelTransform::new(Vec3::ZERO, 0.0, 0.0),
+            CameraTransform::new(Vec3::ZERO, 0.0, 0.0),
+            WorldToScreenTransform::new(90.0, 64.0, 64.0, 0.1, 100.0),
+        )
+    }
+
+    /// Two counter-clockwise triangles covering the whole render target at depth `z`.
+    fn full_screen_quad(z: f32) -> Vec<Vertex> {
+        vec![
+            vertex(-1.0, -1.0, z), vertex(1.0, -1.0, z), vertex(1.0, 1.0, z),
+            vertex(-1.0, -1.0, z), vertex(1.0, 1.0, z), vertex(-1.0, 1.0, z),
+        ]
+    }
+
+    const QUAD_INDICES: [u32; 6] = [0, 1, 2, 3, 4, 5];
+
+    #[test]
+    fn discarded_fragments_leave_stencil_untouched() {
+        for (color, written) in [(None, false), (Some(Vec4::ONE), true)] {
+            let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(color));
+            let mut renderer = Renderer::new(64, 64);
+
+            pipeline.stencil = StencilState { enabled: true, func: CompareFunc::Never, reference: 1, fail_op: StencilOp::Replace, ..StencilState::DISABLED };
+            renderer.draw_indexed(&pipeline, &full_screen_quad(0.5), &QUAD_INDICES, &uniforms());
+            let expected = if written { 1 } else { 0 };
+            assert!(renderer.target.stencil_buffer.iter().all(|&s| s == expected));
+
+            pipeline.stencil = StencilState { enabled: true, reference: 2, depth_fail_op: StencilOp::Replace, ..StencilState::DISABLED };
+            pipeline.depth_func = CompareFunc::Never;
+            renderer.draw_indexed(&pipeline, &full_screen_quad(0.5), &QUAD_INDICES, &uniforms());
+            let expected = if written { 2 } else { 0 };
+            assert!(renderer.target.stencil_buffer.iter().all(|&s| s == expected));
+        }
+    }
+
+    #[test]
+    fn cull_mode_and_front_face_pick_the_triangles_drawn() {
+        let counter_clockwise = [vertex(-0.5, -0.5, 0.5), vertex(0.5, -0.5, 0.5), vertex(0.0, 0.5, 0.5)];
+        // Partly in front of the near plane, clipping must keep the winding
+        let clipped = [vertex(-0.5, -0.5, 0.5), vertex(0.5, -0.5, 0.5), vertex(0.0, 0.5, -0.5)];
+
+        for triangle in [counter_clockwise, clipped] {
+            for counter in [true, false] {
+                let indices = if counter { [0, 1, 2] } else { [0, 2, 1] };
+                for front_face in [FrontFace::CounterClockwise, FrontFace::Clockwise] {
+                    for cull_mode in [CullMode::None, CullMode::Front, CullMode::Back] {
+                        let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(Some(Vec4::ONE)));
+                        pipeline.front_face = front_face;
+                        pipeline.cull_mode = cull_mode;
+                        let mut renderer = Renderer::new(64, 64);
+                        renderer.draw_indexed(&pipeline, &triangle, &indices, &uniforms());
+
+                        let is_front = counter == (front_face == FrontFace::CounterClockwise);
+                        let expected = match cull_mode {
+                            CullMode::None => true,
+                            CullMode::Front => !is_front,
+                            CullMode::Back => is_front,
+                        };
+                        assert!(renderer.target.color_buffer.contains(&Vec4::ONE) == expected);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws a full screen quad at depth `z` through `viewport` and `scissor`, returning which pixels it wrote
+    /// along with the depth buffer.
+    fn draw_clipped(viewport: Option<Viewport>, scissor: Option<ScissorRect>, z: f32) -> (Vec<bool>, Box<[f32]>) {
+        let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(Some(Vec4::ONE)));
+        pipeline.viewport = viewport;
+        pipeline.scissor = scissor;
+
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw_indexed(&pipeline, &full_screen_quad(z), &QUAD_INDICES, &uniforms());
+
+        (renderer.target.color_buffer.iter().map(|&c| c == Vec4::ONE).collect(), renderer.target.depth_buffer.clone())
+    }
+
+    /// Pixels of a 64x64 target with `min.x <= x < max.x` and `min.y <= y < max.y`.
+    fn pixel_rect(min: IVec2, max: IVec2) -> Vec<bool> {
+        (0..64 * 64).map(|i| (min.x..max.x).contains(&(i % 64)) && (min.y..max.y).contains(&(i / 64))).collect()
+    }
+
+    #[test]
+    fn viewport_maps_onto_its_rectangle() {
+        let (covered, _) = draw_clipped(Some(Viewport::new(16.0, 8.0, 32.0, 16.0)), None, 0.5);
+        assert!(covered == pixel_rect(IVec2::new(16, 8), IVec2::new(48, 24)));
+
+        // Only the part inside the target is drawn, without the rest being squeezed into it
+        let (covered, _) = draw_clipped(Some(Viewport::new(-16.0, 40.0, 48.0, 48.0)), None, 0.5);
+        assert!(covered == pixel_rect(IVec2::new(0, 40), IVec2::new(32, 64)));
+    }
+
+    #[test]
+    fn scissor_rect_clips_pixels() {
+        let (covered, _) = draw_clipped(None, Some(ScissorRect::new(10, 20, 5, 30)), 0.5);
+        assert!(covered == pixel_rect(IVec2::new(10, 20), IVec2::new(15, 50)));
+
+        // Clipped against the viewport and the target as well
+        let viewport = Viewport::new(0.0, 0.0, 56.0, 64.0);
+        let (covered, _) = draw_clipped(Some(viewport), Some(ScissorRect::new(50, -10, 100, 20)), 0.5);
+        assert!(covered == pixel_rect(IVec2::new(50, 0), IVec2::new(56, 10)));
+    }
+
+    #[test]
+    fn viewport_depth_range_remaps_depth() {
+        let mut viewport = Viewport::new(0.0, 0.0, 64.0, 64.0);
+        viewport.min_depth = 0.25;
+        viewport.max_depth = 0.75;
+
+        for (z, expected) in [(0.0, 0.25), (0.2, 0.35), (1.0, 0.75)] {
+            let (covered, depth) = draw_clipped(Some(viewport), None, z);
+            assert!(covered.iter().all(|&c| c));
+            assert!(depth.iter().all(|&d| (d - expected).abs() < 1e-6));
+        }
+    }
+
+    #[test]
+    fn negative_depth_bias_draws_decals_over_coplanar_geometry() {
+        let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(Some(Vec4::new(1.0, 0.0, 0.0, 1.0))));
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw_indexed(&pipeline, &full_screen_quad(0.5), &QUAD_INDICES, &uniforms());
+
+        let decal = Vec4::new(0.0, 1.0, 0.0, 1.0);
+        pipeline.fragment_shader = SolidFragmentShader(Some(decal));
+        for (bias, drawn) in [(DepthBias::NONE, false), (DepthBias::new(1.0, 0.0), false), (DepthBias::new(-1.0, 0.0), true)] {
+            pipeline.depth_bias = bias;
+            pipeline.depth_write = false;
+            renderer.draw_indexed(&pipeline, &full_screen_quad(0.5), &QUAD_INDICES, &uniforms());
+            assert!(renderer.target.color_buffer.iter().all(|&c| (c == decal) == drawn));
+        }
+    }
+
+    /// Depth buffer after drawing a quad whose depth goes from `left` to `right` with `bias`, through `viewport`.
+    fn biased_depths(left: f32, right: f32, bias: DepthBias, viewport: Option<Viewport>) -> Box<[f32]> {
+        let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(Some(Vec4::ONE)));
+        pipeline.depth_bias = bias;
+        pipeline.viewport = viewport;
+
+        let quad = [
+            vertex(-1.0, -1.0, left), vertex(1.0, -1.0, right), vertex(1.0, 1.0, right),
+            vertex(-1.0, -1.0, left), vertex(1.0, 1.0, right), vertex(-1.0, 1.0, left),
+        ];
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw_indexed(&pipeline, &quad, &QUAD_INDICES, &uniforms());
+        renderer.target.depth_buffer.clone()
+    }
+
+    #[test]
+    fn slope_scaled_depth_bias_follows_the_depth_gradient() {
+        // 0.4 across 64 pixels
+        let slope = 0.4 / 64.0;
+        let flat = biased_depths(0.2, 0.6, DepthBias::NONE, None);
+        let biased = biased_depths(0.2, 0.6, DepthBias::new(0.0, 2.0), None);
+        assert!(flat.iter().zip(biased.iter()).all(|(&f, &b)| (b - f - 2.0 * slope).abs() < 1e-6));
+
+        // A quad facing the camera has no slope to scale
+        assert!(biased_depths(0.5, 0.5, DepthBias::new(0.0, 2.0), None).iter().all(|&d| d == 0.5));
+    }
+
+    #[test]
+    fn biased_depth_is_clamped_to_the_depth_range() {
+        // The quad spans 0.35 to 0.55 in the depth range, biased by 0.3125 it crosses its far end
+        let mut viewport = Viewport::new(0.0, 0.0, 64.0, 64.0);
+        viewport.min_depth = 0.25;
+        viewport.max_depth = 0.75;
+        let biased = biased_depths(0.2, 0.6, DepthBias::new(0.0, 100.0), Some(viewport));
+        assert!(biased.iter().all(|&d| d <= 0.75) && biased.contains(&0.75) && biased[0] < 0.75);
+
+        let biased = biased_depths(0.2, 0.6, DepthBias::new(0.0, -100.0), Some(viewport));
+        assert!(biased.iter().all(|&d| d == 0.25));
+    }
+
+    /// Writes the position within a point sprite as red and green.
+    struct PointCoordFragmentShader;
+
+    impl FragmentShader for PointCoordFragmentShader {
+        fn shade(&self, fragment: &Fragment) -> Option<Vec4> {
+            Some(Vec4::new(fragment.point_coord.x, fragment.point_coord.y, 0.0, 1.0))
+        }
+    }
+
+    #[test]
+    fn point_sprites_pass_their_corners_to_the_fragment_shader() {
+        let mut pipeline = PipelineState::new(NdcVertexShader, PointCoordFragmentShader);
+        pipeline.topology = PrimitiveTopology::PointList;
+        pipeline.point_size = 8.0;
+
+        // Centred on the corner between pixels 31 and 32
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw(&pipeline, &[vertex(0.0, 0.0, 0.5)], &uniforms());
+
+        let color = |x: i32, y: i32| renderer.target.color_buffer[(y * 64 + x) as usize];
+        assert!(color(28, 28) == Vec4::new(0.0625, 0.0625, 0.0, 1.0));
+        assert!(color(35, 28) == Vec4::new(0.9375, 0.0625, 0.0, 1.0));
+        assert!(color(28, 35) == Vec4::new(0.0625, 0.9375, 0.0, 1.0));
+        assert!(color(35, 35) == Vec4::new(0.9375, 0.9375, 0.0, 1.0));
+        assert!(renderer.target.color_buffer.iter().filter(|&&c| c.w == 1.0).count() == 64);
+    }
+
+    /// Draws `verts` with additive blending at `samples`, returning the red of every sample and of every resolved pixel.
+    fn sample_coverage(samples: SampleCount, verts: &[Vertex]) -> (Vec<f32>, Vec<f32>) {
+        let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(Some(Vec4::new(0.25, 0.25, 0.25, 1.0))));
+        pipeline.blend = BlendState::ADDITIVE;
+
+        let mut renderer = Renderer::new_multisampled(64, 64, samples);
+        renderer.draw(&pipeline, verts, &uniforms());
+        renderer.target.resolve();
+
+        let target = &renderer.target;
+        let sample_colors = if samples == SampleCount::X1 { &target.color_buffer } else { &target.sample_color_buffer };
+        (sample_colors.iter().map(|c| c.x).collect(), target.color_buffer.iter().map(|c| c.x).collect())
+    }
+
+    const SAMPLE_COUNTS: [SampleCount; 4] = [SampleCount::X1, SampleCount::X2, SampleCount::X4, SampleCount::X8];
+
+    #[test]
+    fn shared_edges_cover_every_sample_once() {
+        // A grid of quads with its inner corners pushed around, not so far that any quad folds over,
+        // so edges cross samples at every angle
+        let corner = |i: i32, j: i32| {
+            let inner = if i > 0 && i < 6 && j > 0 && j < 6 { 0.08 } else { 0.0 };
+            let dx = ((i * 7 + j * 3) as f32 * 1.3).sin() * inner;
+            let dy = ((i * 5 + j * 11) as f32 * 0.7).cos() * inner;
+            vertex(i as f32 / 3.0 - 1.0 + dx, j as f32 / 3.0 - 1.0 + dy, 0.5)
+        };
+        let mut mesh = Vec::new();
+        for j in 0..6 {
+            for i in 0..6 {
+                mesh.extend([corner(i, j), corner(i + 1, j), corner(i + 1, j + 1)]);
+                mesh.extend([corner(i, j), corner(i + 1, j + 1), corner(i, j + 1)]);
+            }
+        }
+
+        for samples in SAMPLE_COUNTS {
+            let (sample_colors, resolved) = sample_coverage(samples, &mesh);
+            assert!(sample_colors.len() == 64 * 64 * samples.count());
+            assert!(sample_colors.iter().all(|&c| c == 0.25));
+            assert!(resolved.iter().all(|&c| c == 0.25));
+        }
+    }
+
+    #[test]
+    fn partly_covered_pixels_resolve_to_their_covered_fraction() {
+        // Covers everything right of 1/64th of a pixel past the centre of column 32, closer than any sample
+        // but the single sample's centre, which is left of it
+        let edge = (32.5 + 1.0 / 64.0) / 32.0 - 1.0;
+        let right = [
+            vertex(edge, -1.0, 0.5), vertex(1.0, -1.0, 0.5), vertex(1.0, 1.0, 0.5),
+            vertex(edge, -1.0, 0.5), vertex(1.0, 1.0, 0.5), vertex(edge, 1.0, 0.5),
+        ];
+
+        for samples in SAMPLE_COUNTS {
+            let (_, resolved) = sample_coverage(samples, &right);
+            let fraction = if samples == SampleCount::X1 { 0.0 } else { 0.5 };
+            for (i, &c) in resolved.iter().enumerate() {
+                let expected = match i % 64 {
+                    0..=31 => 0.0,
+                    32 => 0.25 * fraction,
+                    _ => 0.25,
+                };
+                assert!(c == expected);
+            }
+        }
+    }
+
+    /// Draws `verts` with additive blending so every pixel covered once ends up at 0.25.
+    fn coverage_count(topology: PrimitiveTopology, verts: &[Vertex]) -> Vec<f32> {
+        let mut pipeline = PipelineState::new(NdcVertexShader, SolidFragmentShader(Some(Vec4::new(0.25, 0.25, 0.25, 1.0))));
+        pipeline.topology = topology;
+        pipeline.blend = BlendState::ADDITIVE;
+
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw(&pipeline, verts, &uniforms());
+
+        renderer.target.color_buffer.iter().map(|c| c.x).collect()
+    }
+
+    #[test]
+    fn strips_and_fans_cover_every_pixel_once() {
+        // Alternating top and bottom corners from left to right, the odd triangles wind clockwise unless swapped
+        let strip: Vec<Vertex> = [-1.0, -0.3, 0.4, 1.0].iter()
+            .flat_map(|&x| [vertex(x, 1.0, 0.5), vertex(x, -1.0, 0.5)])
+            .collect();
+        assert!(coverage_count(PrimitiveTopology::TriangleStrip, &strip).iter().all(|&c| c == 0.25));
+
+        let fan = [
+            vertex(-1.0, -1.0, 0.5), vertex(1.0, -1.0, 0.5), vertex(1.0, 0.2, 0.5),
+            vertex(1.0, 1.0, 0.5), vertex(-0.1, 1.0, 0.5), vertex(-1.0, 1.0, 0.5),
+        ];
+        assert!(coverage_count(PrimitiveTopology::TriangleFan, &fan).iter().all(|&c| c == 0.25));
+    }
+
+    #[test]
+    fn edges_through_pixel_centres_cover_them_once() {
+        // Eight triangles around the centre of pixel (32, 32), every corner on a pixel centre so that
+        // the horizontal, vertical and diagonal edges between them all run through pixel centres
+        let center = |x: i32, y: i32| vertex((x as f32 + 0.5) / 32.0 - 1.0, (y as f32 + 0.5) / 32.0 - 1.0, 0.5);
+        let ring = [(8, 8), (32, 8), (55, 8), (55, 32), (55, 55), (32, 55), (8, 55), (8, 32)];
+        let mut fan = Vec::new();
+        for i in 0..ring.len() {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            fan.extend([center(32, 32), center(a.0, a.1), center(b.0, b.1)]);
+        }
+
+        let coverage = coverage_count(PrimitiveTopology::TriangleList, &fan);
+        // The left and top edges of the square keep their pixels, the right and bottom ones don't
+        let inside = pixel_rect(IVec2::new(8, 9), IVec2::new(55, 56));
+        assert!(coverage.iter().zip(inside.iter()).all(|(&c, &inside)| c == if inside { 0.25 } else { 0.0 }));
+    }
+
+    #[test]
+    fn grid_through_the_near_plane_covers_every_pixel_once() {
+        // A floor below the camera starting behind it, each quad a pair of triangles sharing edges with its neighbours
+        let corner = |i: i32, j: i32| Vertex::new(Vec3::new(i as f32 * 5.0 - 20.0, j as f32 * 5.0 - 5.0, -1.0), Vec3::ZERO, Vec2::ZERO);
+        let mut grid = Vec::new();
+        for j in 0..12 {
+            for i in 0..8 {
+                grid.extend([corner(i, j), corner(i + 1, j), corner(i + 1, j + 1)]);
+                grid.extend([corner(i, j), corner(i + 1, j + 1), corner(i, j + 1)]);
+            }
+        }
+
+        let mut pipeline = PipelineState::new(BasicVertexShader, SolidFragmentShader(Some(Vec4::new(0.25, 0.25, 0.25, 1.0))));
+        pipeline.blend = BlendState::ADDITIVE;
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw(&pipeline, &grid, &uniforms());
+
+        let coverage: Vec<f32> = renderer.target.color_buffer.iter().map(|c| c.x).collect();
+        assert!(coverage.iter().all(|&c| c == 0.0 || c == 0.25));
+        // Everything below the horizon, a little under the middle of the screen for a floor 55 units deep
+        assert!(coverage[..28 * 64].iter().all(|&c| c == 0.25));
+        assert!(coverage[33 * 64..].iter().all(|&c| c == 0.0));
+    }
+
+    #[test]
+    fn line_strip_matches_line_list() {
+        let points = [vertex(-0.9, -0.8, 0.5), vertex(0.7, -0.2, 0.5), vertex(0.1, 0.9, 0.5), vertex(-0.6, 0.3, 0.5)];
+        let pairs: Vec<Vertex> = points.windows(2).flat_map(|line| [line[0].clone(), line[1].clone()]).collect();
+
+        let strip = coverage_count(PrimitiveTopology::LineStrip, &points);
+        assert!(strip.iter().any(|&c| c > 0.0));
+        assert!(strip == coverage_count(PrimitiveTopology::LineList, &pairs));
+    }
+
+    #[test]
+    fn point_list_draws_a_pixel_per_point() {
+        // Pixel centres of (3, 5), (40, 20) and (63, 63)
+        let center = |x: f32, y: f32| vertex((x + 0.5) / 32.0 - 1.0, (y + 0.5) / 32.0 - 1.0, 0.5);
+        let points = [center(3.0, 5.0), center(40.0, 20.0), center(63.0, 63.0)];
+
+        let coverage = coverage_count(PrimitiveTopology::PointList, &points);
+        let lit: Vec<usize> = (0..coverage.len()).filter(|&i| coverage[i] > 0.0).collect();
+        assert!(lit == [5 * 64 + 3, 20 * 64 + 40, 63 * 64 + 63]);
+    }
+
+    #[test]
+    fn instanced_draw_matches_separate_draws() {
+        let cube = crate::model::Model::load_from_data(include_str!("../cube.obj")).unwrap();
+        let pipeline = PipelineState::new(BasicVertexShader, NormalFragmentShader);
+        let camera = CameraTransform::new(Vec3::ZERO, 0.0, 0.0);
+        let projection = WorldToScreenTransform::new(90.0, 64.0, 64.0, 0.1, 100.0);
+        let models: Vec<ModelTransform> = (0..12)
+            .map(|i| ModelTransform::new(Vec3::new((i % 4) as f32 * 2.5 - 4.0, (i / 4) as f32 * 2.5 - 2.5, 6.0 + (i % 3) as f32), i as f32 * 0.4, i as f32 * 0.3))
+            .collect();
+
+        let mut separate = Renderer::new(64, 64);
+        for model in &models {
+            separate.draw_indexed(&pipeline, &cube.verts, &cube.indices, &Uniforms::new(model.clone(), camera.clone(), projection.clone()));
+        }
+
+        let mut instanced = Renderer::new(64, 64);
+        let instances: Vec<Instance> = models.iter().map(|model| Instance::new(model.clone())).collect();
+        instanced.draw_instanced(&pipeline, &cube.verts, &cube.indices, &instances, &uniforms());
+
+        assert!(separate.target.color_buffer.iter().any(|c| c.w != 0.0));
+        assert!(separate.target.color_buffer == instanced.target.color_buffer);
+    }
+
+    #[test]
+    fn worker_threads_render_the_same_as_one_thread() {
+        let cube = crate::model::Model::load_from_data(include_str!("../cube.obj")).unwrap();
+        let pipeline = PipelineState::new(BasicVertexShader, NormalFragmentShader);
+        let camera = CameraTransform::new(Vec3::ZERO, 0.0, 0.0);
+        let projection = WorldToScreenTransform::new(90.0, 256.0, 256.0, 0.1, 100.0);
+        // Overlapping cubes all over the target, so many primitives land in every row of tiles
+        let models: Vec<ModelTransform> = (0..60)
+            .map(|i| ModelTransform::new(Vec3::new((i % 6) as f32 * 1.3 - 3.3, (i / 6) as f32 * 0.7 - 3.2, 4.0 + (i % 7) as f32 * 0.4), i as f32 * 0.5, i as f32 * 0.3))
+            .collect();
+
+        let render = |thread_count: usize| {
+            let mut renderer = Renderer::new_multisampled(256, 256, SampleCount::X4);
+            renderer.thread_count = thread_count;
+            renderer.clear_color(Vec4::new(0.1, 0.2, 0.3, 1.0));
+            for model in &models {
+                renderer.draw_indexed(&pipeline, &cube.verts, &cube.indices, &Uniforms::new(model.clone(), camera.clone(), projection.clone()));
+            }
+
+            // The last draw had work in more than one row of tiles, so it went to the workers
+            let bins = &renderer.bins;
+            assert!((0..bins.tiles_y).filter(|&tile_y| bins.row_has_work(tile_y)).count() > 1);
+            assert!(renderer.workers.workers() == thread_count - 1);
+
+            renderer.target.resolve();
+            (renderer.target.color_buffer, renderer.target.depth_buffer)
+        };
+
+        let single = render(1);
+        for thread_count in [2, 3, 8] {
+            assert!(render(thread_count) == single);
+        }
+    }
+
+    /// Moves the vertex by the instance data and passes on the instance colour and index.
+    struct InstanceVertexShader;
+
+    impl VertexShader for InstanceVertexShader {
+        fn shade(&self, input: &VertexInput, out: &mut Varyings) -> Vec4 {
+            let instance = input.instance.unwrap();
+            out.set_vec3(0, instance.color.xyz());
+            out.data[3] = input.instance_index as f32;
+
+            (input.vertex.position + instance.data.xyz()).v4()
+        }
+
+        fn varying_count(&self) -> usize {
+            4
+        }
+    }
+
+    struct VaryingFragmentShader;
+
+    impl FragmentShader for VaryingFragmentShader {
+        fn shade(&self, fragment: &Fragment) -> Option<Vec4> {
+            Some(fragment.varyings.get_vec4(0))
+        }
+    }
+
+    #[test]
+    fn instance_data_reaches_the_shaders() {
+        let pipeline = PipelineState::new(InstanceVertexShader, VaryingFragmentShader);
+        let left_half = [
+            vertex(-1.0, -1.0, 0.5), vertex(0.0, -1.0, 0.5), vertex(0.0, 1.0, 0.5),
+            vertex(-1.0, -1.0, 0.5), vertex(0.0, 1.0, 0.5), vertex(-1.0, 1.0, 0.5),
+        ];
+
+        let mut instances = [Instance::new(ModelTransform::new(Vec3::ZERO, 0.0, 0.0)), Instance::new(ModelTransform::new(Vec3::ZERO, 0.0, 0.0))];
+        instances[0].color = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        instances[1].color = Vec4::new(0.0, 1.0, 0.0, 1.0);
+        instances[1].data = Vec4::new(1.0, 0.0, 0.0, 0.0);
+
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw_instanced(&pipeline, &left_half, &[0, 1, 2, 3, 4, 5], &instances, &uniforms());
+
+        let pixel = |x: i32, y: i32| renderer.target.color_buffer[(y * 64 + x) as usize];
+        assert!(pixel(10, 30) == Vec4::new(1.0, 0.0, 0.0, 0.0));
+        assert!(pixel(50, 30) == Vec4::new(0.0, 1.0, 0.0, 1.0));
+    }
+
+    /// Draws the cube in a few poses, then redraws it in white with `func`, optionally forcing every sample through the depth test.
+    fn redraw_cubes(func: CompareFunc, coarse_depth: bool) -> Box<[Vec4]> {
+        let cube = crate::model::Model::load_from_data(include_str!("../cube.obj")).unwrap();
+        let camera = CameraTransform::new(Vec3::ZERO, 0.0, 0.0);
+        let projection = WorldToScreenTransform::new(90.0, 128.0, 128.0, 0.1, 100.0);
+        // Every third cube faces the camera, so the depth of its front face is the same at every vertex
+        let models: Vec<ModelTransform> = (0..40)
+            .map(|i| {
+                let spin = if i % 3 == 0 { 0.0 } else { i as f32 };
+                ModelTransform::new(Vec3::new((i % 8) as f32 * 1.7 - 6.0, (i / 8) as f32 * 1.7 - 3.5, 5.0 + (i % 5) as f32 * 1.3), spin * 0.37, spin * 0.23)
+            })
+            .collect();
+
+        let mut renderer = Renderer::new(128, 128);
+        let pipeline = PipelineState::new(BasicVertexShader, NormalFragmentShader);
+        for model in &models {
+            renderer.draw_indexed(&pipeline, &cube.verts, &cube.indices, &Uniforms::new(model.clone(), camera.clone(), projection.clone()));
+        }
+
+        let mut redraw = PipelineState::new(BasicVertexShader, SolidFragmentShader(Some(Vec4::ONE)));
+        redraw.depth_func = func;
+        redraw.depth_write = false;
+        if !coarse_depth {
+            // Updating the stencil on depth failures turns off coarse depth rejection
+            redraw.stencil = StencilState { enabled: true, depth_fail_op: StencilOp::Replace, ..StencilState::DISABLED };
+        }
+        for model in &models {
+            renderer.draw_indexed(&redraw, &cube.verts, &cube.indices, &Uniforms::new(model.clone(), camera.clone(), projection.clone()));
+        }
+
+        renderer.target.color_buffer.clone()
+    }
+
+    #[test]
+    fn coarse_depth_keeps_equal_depth_redraws() {
+        for func in [CompareFunc::LessEqual, CompareFunc::Equal] {
+            let exact = redraw_cubes(func, false);
+            assert!(exact.contains(&Vec4::ONE));
+            assert!(exact.iter().all(|&c| c == Vec4::ONE || c == Vec4::ZERO));
+            assert!(redraw_cubes(func, true) == exact);
+        }
+    }
+
+    /// Like `NdcVertexShader`, but scales the position by the w in the x of the normal.
+    struct HomogeneousVertexShader;
+
+    impl VertexShader for HomogeneousVertexShader {
+        fn shade(&self, input: &VertexInput, out: &mut Varyings) -> Vec4 {
+            out.set_vec2(0, input.vertex.tex_coords);
+
+            let w = input.vertex.normal.x;
+            Vec4::new(input.vertex.position.x * w, input.vertex.position.y * w, input.vertex.position.z * w, w)
+        }
+
+        fn varying_count(&self) -> usize {
+            2
+        }
+    }
+
+    /// Writes the texture coordinates passed on by the vertex shader as red and green.
+    struct TexCoordFragmentShader;
+
+    impl FragmentShader for TexCoordFragmentShader {
+        fn shade(&self, fragment: &Fragment) -> Option<Vec4> {
+            let uv = fragment.varyings.get_vec2(0);
+            Some(Vec4::new(uv.x, uv.y, 0.0, 1.0))
+        }
+    }
+
+    #[test]
+    fn varyings_are_perspective_correct() {
+        // A rectangle slanting away to the right, four times as far away at its right edge as at its left,
+        // so that edge is a quarter as tall on screen
+        let corner = |x: f32, y: f32| {
+            let w = if x < 0.0 { 1.0 } else { 4.0 };
+            Vertex::new(Vec3::new(x, y / w, 0.5), Vec3::new(w, 0.0, 0.0), Vec2::new((x + 1.0) * 0.5, (y + 1.0) * 0.5))
+        };
+        let quad = [
+            corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0),
+            corner(-1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0),
+        ];
+
+        let pipeline = PipelineState::new(HomogeneousVertexShader, TexCoordFragmentShader);
+        let mut renderer = Renderer::new(64, 64);
+        renderer.draw(&pipeline, &quad, &uniforms());
+
+        for (i, &c) in renderer.target.color_buffer.iter().enumerate() {
+            let x = ((i % 64) as f32 + 0.5) / 32.0 - 1.0;
+            let y = ((i / 64) as f32 + 0.5) / 32.0 - 1.0;
+            // Where the view ray through the pixel centre hits the rectangle, x and y are only linear in
+            // the texture coordinates after the division by w
+            let u = (1.0 + x) / (5.0 - 3.0 * x);
+            let w = 1.0 + 3.0 * u;
+            let v = (y * w + 1.0) * 0.5;
+
+            if (y * w).abs() < 0.95 {
+                assert!(c.w == 1.0);
+            }
+            if c.w == 1.0 {
+                assert!((c.x - u).abs() < 1e-5 && (c.y - v).abs() < 1e-5);
+            }
+        }
+
+        // Halfway across the screen is only a fifth of the way across the rectangle
+        assert!((renderer.target.color_buffer[32 * 64 + 32].x - 0.2).abs() < 0.01);
+    }
+}
@@ -1,20 +1,218 @@
 use crate::vmath::*;
+use crate::pipeline::CompareFunc;
+use crate::simd::BLOCK_SIZE;
+use crate::tiles::TILE_SIZE;
 
 
+/// Most samples a pixel can have.
+pub const MAX_SAMPLES: usize = 8;
+
+const PATTERN_X1: [IVec2; 1] = [IVec2::new(0, 0)];
+const PATTERN_X2: [IVec2; 2] = [IVec2::new(4, -4), IVec2::new(-4, 4)];
+const PATTERN_X4: [IVec2; 4] = [IVec2::new(-2, 6), IVec2::new(6, 2), IVec2::new(-6, -2), IVec2::new(2, -6)];
+const PATTERN_X8: [IVec2; 8] = [
+    IVec2::new(1, 3), IVec2::new(-1, -3), IVec2::new(5, -1), IVec2::new(-3, 5),
+    IVec2::new(-5, -5), IVec2::new(-7, 1), IVec2::new(3, -7), IVec2::new(7, 7),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SampleCount {
+    X1,
+    X2,
+    X4,
+    X8,
+}
+
+impl SampleCount {
+    #[inline]
+    pub const fn count(self) -> usize {
+        match self {
+            SampleCount::X1 => 1,
+            SampleCount::X2 => 2,
+            SampleCount::X4 => 4,
+            SampleCount::X8 => 8,
+        }
+    }
+
+    /// Sample positions relative to the pixel centre in 1/16th of a pixel. These are the standard
+    /// D3D rotated grid patterns, flipped vertically since y points up here.
+    pub const fn pattern(self) -> &'static [IVec2] {
+        match self {
+            SampleCount::X1 => &PATTERN_X1,
+            SampleCount::X2 => &PATTERN_X2,
+            SampleCount::X4 => &PATTERN_X4,
+            SampleCount::X8 => &PATTERN_X8,
+        }
+    }
+}
+
+/// Bounds of the depth values of every sample in a square of pixels.
+#[derive(Clone, Copy)]
+pub struct DepthBounds {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl DepthBounds {
+    #[inline]
+    pub const fn new(min: f32, max: f32) -> Self {
+        Self { min: min, max: max }
+    }
+
+    /// Whether every depth in `min`..`max` is certain to fail the depth test against these bounds.
+    #[inline]
+    pub fn occludes(self, func: CompareFunc, min: f32, max: f32) -> bool {
+        match func {
+            CompareFunc::Never => true,
+            CompareFunc::Less => min >= self.max,
+            CompareFunc::LessEqual => min > self.max,
+            CompareFunc::Greater => max <= self.min,
+            CompareFunc::GreaterEqual => max < self.min,
+            CompareFunc::Equal => min > self.max || max < self.min,
+            CompareFunc::NotEqual | CompareFunc::Always => false,
+        }
+    }
+
+    #[inline]
+    pub fn include(&mut self, depth: f32) {
+        self.min = self.min.min(depth);
+        self.max = self.max.max(depth);
+    }
+}
+
+/// A horizontal band of the render target that can be rendered to independently of the others.
+pub struct TileRow<'a> {
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub sample_pattern: &'static [IVec2],
+    /// Every buffer holds `sample_pattern.len()` consecutive samples per pixel.
+    pub color_buffer: &'a mut [Vec4],
+    pub depth_buffer: &'a mut [f32],
+    pub stencil_buffer: &'a mut [u8],
+    /// Depth bounds of every block and of every tile in the band, see `RenderTarget::block_depth`.
+    pub block_depth: &'a mut [DepthBounds],
+    pub tile_depth: &'a mut [DepthBounds],
+}
+
+impl TileRow<'_> {
+    /// Pixel index of a pixel given in render target coordinates, multiply by the sample count for the first sample.
+    #[inline]
+    pub fn index(&self, x: i32, y: i32) -> usize {
+        ((y - self.y) * self.width + x) as usize
+    }
+
+    /// Index into `block_depth` of the block containing the pixel at (x, y).
+    #[inline]
+    pub fn block_index(&self, x: i32, y: i32) -> usize {
+        ((y - self.y) / BLOCK_SIZE * blocks_across(self.width) + x / BLOCK_SIZE) as usize
+    }
+
+    /// Recomputes the bounds of the block containing the pixel at (x, y) from its samples.
+    pub fn update_block_depth(&mut self, x: i32, y: i32) {
+        let samples = self.sample_pattern.len();
+        let block_x = x & !(BLOCK_SIZE - 1);
+        let block_y = y & !(BLOCK_SIZE - 1);
+
+        let mut bounds = DepthBounds::new(f32::INFINITY, f32::NEG_INFINITY);
+        for y in block_y..(block_y + BLOCK_SIZE).min(self.y + self.height) {
+            let first = self.index(block_x, y) * samples;
+            let last = self.index((block_x + BLOCK_SIZE).min(self.width), y) * samples;
+            for &depth in &self.depth_buffer[first..last] {
+                bounds.include(depth);
+            }
+        }
+
+        let idx = self.block_index(x, y);
+        self.block_depth[idx] = bounds;
+    }
+
+    /// Widens the bounds of the block and tile containing the pixel at (x, y) to include `depth`.
+    #[inline]
+    pub fn include_depth(&mut self, x: i32, y: i32, depth: f32) {
+        let idx = self.block_index(x, y);
+        self.block_depth[idx].include(depth);
+        self.tile_depth[(x / TILE_SIZE) as usize].include(depth);
+    }
+
+    /// Recomputes the bounds of a tile from the bounds of its blocks.
+    pub fn update_tile_depth(&mut self, tile_x: i32) {
+        let blocks_x = blocks_across(self.width);
+        let first_x = tile_x * TILE_SIZE / BLOCK_SIZE;
+        let last_x = ((tile_x + 1) * TILE_SIZE / BLOCK_SIZE).min(blocks_x);
+
+        let mut bounds = DepthBounds::new(f32::INFINITY, f32::NEG_INFINITY);
+        for blocks in self.block_depth.chunks_exact(blocks_x as usize) {
+            for block in &blocks[first_x as usize..last_x as usize] {
+                bounds.include(block.min);
+                bounds.include(block.max);
+            }
+        }
+
+        self.tile_depth[tile_x as usize] = bounds;
+    }
+}
+
+#[inline]
+fn blocks_across(width: i32) -> i32 {
+    (width + BLOCK_SIZE - 1) / BLOCK_SIZE
+}
+
+#[inline]
+fn tiles_across(width: i32) -> i32 {
+    (width + TILE_SIZE - 1) / TILE_SIZE
+}
+
 pub struct RenderTarget {
     pub width: i32,
     pub height: i32,
+    pub samples: SampleCount,
+    /// One colour per pixel. Multisampled targets render into `sample_color_buffer` and
+    /// only get their colours here once resolved.
     pub color_buffer: Box<[Vec4]>,
+    /// Colour of every sample, empty unless multisampled.
+    pub sample_color_buffer: Box<[Vec4]>,
+    /// Only change through the renderer, the depth bounds below have to be kept in sync with it.
     pub depth_buffer: Box<[f32]>,
+    pub stencil_buffer: Box<[u8]>,
+    /// Coarse depth, the bounds of every `BLOCK_SIZE` and every `TILE_SIZE` square of pixels,
+    /// so occluded geometry can be rejected without testing each sample.
+    pub block_depth: Box<[DepthBounds]>,
+    pub tile_depth: Box<[DepthBounds]>,
 }
 
 impl RenderTarget {
     pub fn new(width: i32, height: i32) -> Self {
+        Self::new_multisampled(width, height, SampleCount::X1)
+    }
+
+    pub fn new_multisampled(width: i32, height: i32, samples: SampleCount) -> Self {
+        let pixels = (width * height) as usize;
+        let sample_colors = if samples == SampleCount::X1 { 0 } else { pixels * samples.count() };
+
         Self {
             width: width,
             height: height,
-            color_buffer: vec![Vec4::ZERO; (width * height) as usize].into_boxed_slice(),
-            depth_buffer: vec![0.0; (width * height) as usize].into_boxed_slice(),
+            samples: samples,
+            color_buffer: vec![Vec4::ZERO; pixels].into_boxed_slice(),
+            sample_color_buffer: vec![Vec4::ZERO; sample_colors].into_boxed_slice(),
+            depth_buffer: vec![1.0; pixels * samples.count()].into_boxed_slice(),
+            stencil_buffer: vec![0; pixels * samples.count()].into_boxed_slice(),
+            block_depth: vec![DepthBounds::new(1.0, 1.0); (blocks_across(width) * blocks_across(height)) as usize].into_boxed_slice(),
+            tile_depth: vec![DepthBounds::new(1.0, 1.0); (tiles_across(width) * tiles_across(height)) as usize].into_boxed_slice(),
+        }
+    }
+
+    /// Averages the samples of every pixel into `color_buffer`, nothing to do for single sampled targets.
+    pub fn resolve(&mut self) {
+        let samples = self.samples.count();
+        if samples == 1 {
+            return;
+        }
+
+        let scale = 1.0 / samples as f32;
+        for (color, pixel) in self.color_buffer.iter_mut().zip(self.sample_color_buffer.chunks_exact(samples)) {
+            *color = pixel.iter().fold(Vec4::ZERO, |sum, &sample| sum + sample) * scale;
         }
     }
 
@@ -38,5 +236,40 @@ impl RenderTarget {
         res
     }
 
+    pub fn clear_depth(&mut self, depth: f32) {
+        self.depth_buffer.iter_mut().for_each(|x| *x = depth);
+        self.block_depth.iter_mut().for_each(|x| *x = DepthBounds::new(depth, depth));
+        self.tile_depth.iter_mut().for_each(|x| *x = DepthBounds::new(depth, depth));
+    }
+
+    /// Splits the render target into bands of `TILE_SIZE` rows each, the last one may be shorter.
+    pub fn tile_rows(&mut self) -> Vec<TileRow<'_>> {
+        let rows = TILE_SIZE;
+        let samples = self.samples.count();
+        let band = (rows * self.width) as usize * samples;
+        let width = self.width;
+        let pattern = self.samples.pattern();
+
+        let color_buffer = if samples == 1 { &mut self.color_buffer } else { &mut self.sample_color_buffer };
+        color_buffer.chunks_mut(band)
+            .zip(self.depth_buffer.chunks_mut(band))
+            .zip(self.stencil_buffer.chunks_mut(band))
+            .zip(self.block_depth.chunks_mut((blocks_across(width) * TILE_SIZE / BLOCK_SIZE) as usize))
+            .zip(self.tile_depth.chunks_mut(tiles_across(width) as usize))
+            .enumerate()
+            .map(|(i, ((((color, depth), stencil), block_depth), tile_depth))| TileRow {
+                y: i as i32 * rows,
+                width: width,
+                height: (color.len() / samples) as i32 / width,
+                sample_pattern: pattern,
+                color_buffer: color,
+                depth_buffer: depth,
+                stencil_buffer: stencil,
+                block_depth: block_depth,
+                tile_depth: tile_depth,
+            })
+            .collect()
+    }
+
 }
 
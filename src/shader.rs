@@ -0,0 +1,277 @@
+use crate::vmath::*;
+use crate::transform::{Transform, ModelTransform, CameraTransform, WorldToScreenTransform};
+use crate::texture::{Texture, Sampler};
+
+
+pub const MAX_VARYINGS: usize = 16;
+
+/// Values written by the vertex shader and interpolated across the primitive for the fragment shader.
+#[derive(Clone, Copy)]
+pub struct Varyings {
+    pub data: [f32; MAX_VARYINGS],
+}
+
+impl Varyings {
+    /// All zeroes.
+    pub const ZERO: Self = Self { data: [0.0; MAX_VARYINGS] };
+
+    #[inline]
+    pub fn get_vec2(&self, at: usize) -> Vec2 {
+        Vec2::new(self.data[at], self.data[at+1])
+    }
+
+    #[inline]
+    pub fn get_vec3(&self, at: usize) -> Vec3 {
+        Vec3::new(self.data[at], self.data[at+1], self.data[at+2])
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    pub fn get_vec4(&self, at: usize) -> Vec4 {
+        Vec4::new(self.data[at], self.data[at+1], self.data[at+2], self.data[at+3])
+    }
+
+    #[inline]
+    pub fn set_vec2(&mut self, at: usize, v: Vec2) {
+        self.data[at] = v.x;
+        self.data[at+1] = v.y;
+    }
+
+    #[inline]
+    pub fn set_vec3(&mut self, at: usize, v: Vec3) {
+        self.data[at] = v.x;
+        self.data[at+1] = v.y;
+        self.data[at+2] = v.z;
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    pub fn set_vec4(&mut self, at: usize, v: Vec4) {
+        self.data[at] = v.x;
+        self.data[at+1] = v.y;
+        self.data[at+2] = v.z;
+        self.data[at+3] = v.w;
+    }
+
+    pub fn lerp(a: &Varyings, b: &Varyings, t: f32) -> Self {
+        let mut r = Self::ZERO;
+        for i in 0..MAX_VARYINGS {
+            r.data[i] = a.data[i] + (b.data[i] - a.data[i]) * t;
+        }
+
+        r
+    }
+
+    /// `b - a` for the first `count` varyings.
+    pub fn difference(a: &Varyings, b: &Varyings, count: usize) -> Self {
+        let mut r = Self::ZERO;
+        for i in 0..count {
+            r.data[i] = b.data[i] - a.data[i];
+        }
+
+        r
+    }
+
+    /// Blends the first `count` varyings of three sets with the given barycentric weights.
+    pub fn interpolate(a: &Varyings, b: &Varyings, c: &Varyings, weights: Vec3, count: usize) -> Self {
+        let mut r = Self::ZERO;
+        for i in 0..count {
+            r.data[i] = a.data[i] * weights.x + b.data[i] * weights.y + c.data[i] * weights.z;
+        }
+
+        r
+    }
+}
+
+/// Uniforms every draw provides, the transforms are calculated once up front.
+#[derive(Clone)]
+pub struct Uniforms {
+    pub model: ModelTransform,
+    pub camera: CameraTransform,
+    pub projection: WorldToScreenTransform,
+}
+
+impl Uniforms {
+    pub fn new(model: ModelTransform, camera: CameraTransform, projection: WorldToScreenTransform) -> Self {
+        let mut r = Self { model: model, camera: camera, projection: projection };
+        r.model.calculate_transform();
+        r.camera.calculate_transform();
+        r.projection.calculate_transform();
+
+        r
+    }
+
+    /// Object space to view space.
+    pub fn to_view(&self, p: Vec3) -> Vec3 {
+        let mut r = p;
+        self.model.apply_transform(&mut r);
+        self.camera.apply_transform(&mut r);
+
+        r
+    }
+
+    /// Object space to homogeneous clip space.
+    pub fn to_clip(&self, p: Vec3) -> Vec4 {
+        self.projection.project(self.to_view(p))
+    }
+
+    /// World space to homogeneous clip space, skipping the model transform.
+    pub fn world_to_clip(&self, p: Vec3) -> Vec4 {
+        let mut r = p;
+        self.camera.apply_transform(&mut r);
+
+        self.projection.project(r)
+    }
+}
+
+/// Per instance data of an instanced draw, takes the place of the model transform in the uniforms.
+#[derive(Clone)]
+pub struct Instance {
+    pub model: ModelTransform,
+    /// Not read by the built-in shaders, for custom ones to use.
+    #[allow(dead_code)]
+    pub color: Vec4,
+    /// Free for shaders to use however they like.
+    #[allow(dead_code)]
+    pub data: Vec4,
+}
+
+impl Instance {
+    pub fn new(model: ModelTransform) -> Self {
+        let mut r = Self { model: model, color: Vec4::ONE, data: Vec4::ZERO };
+        r.model.calculate_transform();
+
+        r
+    }
+
+    /// Object space to world space.
+    pub fn to_world(&self, p: Vec3) -> Vec3 {
+        let mut r = p;
+        self.model.apply_transform(&mut r);
+
+        r
+    }
+}
+
+pub struct VertexInput<'a> {
+    pub vertex: &'a Vertex,
+    pub uniforms: &'a Uniforms,
+    /// Index into the instance buffer, always 0 outside of instanced draws.
+    #[allow(dead_code)]
+    pub instance_index: u32,
+    pub instance: Option<&'a Instance>,
+}
+
+impl VertexInput<'_> {
+    /// Object space to homogeneous clip space, using the instance transform in instanced draws.
+    pub fn to_clip(&self, p: Vec3) -> Vec4 {
+        match self.instance {
+            Some(instance) => self.uniforms.world_to_clip(instance.to_world(p)),
+            None => self.uniforms.to_clip(p),
+        }
+    }
+}
+
+pub trait VertexShader: Sync {
+    /// Returns the clip space position of the vertex and fills in the varyings to interpolate.
+    fn shade(&self, input: &VertexInput, out: &mut Varyings) -> Vec4;
+
+    /// How many of the varyings are written, only those get interpolated per fragment.
+    fn varying_count(&self) -> usize {
+        MAX_VARYINGS
+    }
+}
+
+pub struct Fragment {
+    /// Pixel centre in x and y, depth in z.
+    #[allow(dead_code)]
+    pub position: Vec3,
+    pub varyings: Varyings,
+    /// Position within a point sprite, (0, 0) in the bottom left to (1, 1) in the top right. Zero for lines and triangles.
+    pub point_coord: Vec2,
+    /// Change of the varyings per pixel right and up. Only filled in for triangles, and only if
+    /// the shader asks for them with `FragmentShader::needs_derivatives`.
+    pub ddx: Varyings,
+    pub ddy: Varyings,
+}
+
+pub trait FragmentShader: Sync {
+    /// Returns the colour of the fragment, or `None` to discard it.
+    fn shade(&self, fragment: &Fragment) -> Option<Vec4>;
+
+    /// Whether `Fragment::ddx` and `Fragment::ddy` are used, they cost two more interpolations per fragment.
+    fn needs_derivatives(&self) -> bool {
+        false
+    }
+}
+
+/// Transforms positions with the draw uniforms and passes the normal and texture coordinates on.
+pub struct BasicVertexShader;
+
+impl BasicVertexShader {
+    pub const NORMAL: usize = 0;
+    pub const TEX_COORDS: usize = 3;
+}
+
+impl VertexShader for BasicVertexShader {
+    fn shade(&self, input: &VertexInput, out: &mut Varyings) -> Vec4 {
+        out.set_vec3(Self::NORMAL, input.vertex.normal);
+        out.set_vec2(Self::TEX_COORDS, input.vertex.tex_coords);
+
+        input.to_clip(input.vertex.position)
+    }
+
+    fn varying_count(&self) -> usize {
+        5
+    }
+}
+
+/// Visualizes the interpolated normal written by `BasicVertexShader`.
+pub struct NormalFragmentShader;
+
+impl FragmentShader for NormalFragmentShader {
+    #[inline]
+    fn shade(&self, fragment: &Fragment) -> Option<Vec4> {
+        let n = fragment.varyings.get_vec3(BasicVertexShader::NORMAL).normalize();
+
+        Some(Vec4::new(n.x * 0.5 + 0.5, n.y * 0.5 + 0.5, n.z * 0.5 + 0.5, 1.0))
+    }
+}
+
+/// Samples a texture at the texture coordinates written by `BasicVertexShader`, picking the mip level from their derivatives.
+pub struct TextureFragmentShader<'a> {
+    pub texture: &'a Texture,
+    pub sampler: Sampler,
+}
+
+impl FragmentShader for TextureFragmentShader<'_> {
+    #[inline]
+    fn shade(&self, fragment: &Fragment) -> Option<Vec4> {
+        let uv = fragment.varyings.get_vec2(BasicVertexShader::TEX_COORDS);
+        let ddx = fragment.ddx.get_vec2(BasicVertexShader::TEX_COORDS);
+        let ddy = fragment.ddy.get_vec2(BasicVertexShader::TEX_COORDS);
+
+        Some(self.sampler.sample_grad(self.texture, uv, ddx, ddy))
+    }
+
+    fn needs_derivatives(&self) -> bool {
+        true
+    }
+}
+
+/// Draws point sprites as round dots of a single colour, discarding the corners of the square.
+pub struct DotFragmentShader {
+    pub color: Vec4,
+}
+
+impl FragmentShader for DotFragmentShader {
+    #[inline]
+    fn shade(&self, fragment: &Fragment) -> Option<Vec4> {
+        let offset = fragment.point_coord - Vec2::splat(0.5);
+        if dot(offset, offset) > 0.25 {
+            return None;
+        }
+
+        Some(self.color)
+    }
+}
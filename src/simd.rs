@@ -0,0 +1,232 @@
+use crate::vmath::*;
+use crate::raster::*;
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+
+/// Triangles are walked in square blocks of pixels, evaluated all at once.
+pub const BLOCK_SIZE: i32 = 4;
+pub const BLOCK_PIXELS: usize = (BLOCK_SIZE * BLOCK_SIZE) as usize;
+
+/// In-block edge offsets have to stay below this for the i32 lanes, see `BlockSetup::coverage`.
+const LANE_LIMIT: i64 = 1 << 30;
+
+/// Per triangle constants for evaluating edge functions over a block.
+/// Bit `y * BLOCK_SIZE + x` of a coverage mask is the pixel at (x, y) from the block origin.
+#[derive(Clone, Copy)]
+pub struct BlockSetup {
+    step_x: [i64; 3],
+    step_y: [i64; 3],
+    bias: [i64; 3],
+    /// Whether every offset from the block origin fits in an i32 lane.
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    fits_lanes: bool,
+}
+
+impl BlockSetup {
+    pub fn new(setup: &TriangleSetup) -> Self {
+        let step_x = [setup.edges[0].step_x, setup.edges[1].step_x, setup.edges[2].step_x];
+        let step_y = [setup.edges[0].step_y, setup.edges[1].step_y, setup.edges[2].step_y];
+        let span = (BLOCK_SIZE - 1) as i64;
+
+        Self {
+            step_x: step_x,
+            step_y: step_y,
+            bias: [setup.edges[0].bias, setup.edges[1].bias, setup.edges[2].bias],
+            fits_lanes: (0..3).all(|i| span * (step_x[i].abs() + step_y[i].abs()) < LANE_LIMIT),
+        }
+    }
+
+    /// Coverage mask of the block whose first pixel has the edge values `e`.
+    #[inline]
+    pub fn coverage(&self, e: [i64; 3]) -> u16 {
+        #[cfg(target_arch = "x86_64")]
+        if self.fits_lanes {
+            return self.coverage_sse2(e);
+        }
+
+        self.coverage_scalar(e)
+    }
+
+    fn coverage_scalar(&self, e: [i64; 3]) -> u16 {
+        let mut mask = 0u16;
+        for y in 0..BLOCK_SIZE as i64 {
+            for x in 0..BLOCK_SIZE as i64 {
+                let covered = (0..3).all(|i| e[i] + x * self.step_x[i] + y * self.step_y[i] + self.bias[i] >= 0);
+                if covered {
+                    mask |= 1 << (y * BLOCK_SIZE as i64 + x);
+                }
+            }
+        }
+
+        mask
+    }
+
+    /// A pixel is covered when `e + offset + bias >= 0`, i.e. `offset > -(e + bias) - 1`.
+    /// The offset from the block origin is small enough for i32 lanes while `e` may not be, but
+    /// since |offset| < LANE_LIMIT, clamping the threshold to that range doesn't change the outcome.
+    #[cfg(target_arch = "x86_64")]
+    fn coverage_sse2(&self, e: [i64; 3]) -> u16 {
+        // SAFETY: SSE2 is part of the x86_64 baseline
+        unsafe {
+            let mut rows = [_mm_set1_epi32(-1); BLOCK_SIZE as usize];
+
+            for (i, e) in e.iter().enumerate() {
+                let threshold = _mm_set1_epi32(clamp(-(e + self.bias[i]) - 1, -LANE_LIMIT, LANE_LIMIT) as i32);
+                let step_x = self.step_x[i] as i32;
+                let step_y = _mm_set1_epi32(self.step_y[i] as i32);
+
+                let mut offsets = _mm_setr_epi32(0, step_x, 2 * step_x, 3 * step_x);
+                for row in rows.iter_mut() {
+                    *row = _mm_and_si128(*row, _mm_cmpgt_epi32(offsets, threshold));
+                    offsets = _mm_add_epi32(offsets, step_y);
+                }
+            }
+
+            let mut mask = 0u16;
+            for (y, row) in rows.iter().enumerate() {
+                mask |= (_mm_movemask_ps(_mm_castsi128_ps(*row)) as u16) << (y * BLOCK_SIZE as usize);
+            }
+
+            mask
+        }
+    }
+}
+
+/// Evaluates the plane `origin + x * ddx + y * ddy` at every pixel of a block.
+#[inline]
+pub fn eval_plane(origin: f32, ddx: f32, ddy: f32, out: &mut [f32; BLOCK_PIXELS]) {
+    #[cfg(target_arch = "x86_64")]
+    eval_plane_sse2(origin, ddx, ddy, out);
+
+    #[cfg(not(target_arch = "x86_64"))]
+    eval_plane_scalar(origin, ddx, ddy, out);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn eval_plane_sse2(origin: f32, ddx: f32, ddy: f32, out: &mut [f32; BLOCK_PIXELS]) {
+    // SAFETY: SSE2 is part of the x86_64 baseline, each chunk holds exactly four f32s and
+    // storeu has no alignment requirement
+    unsafe {
+        let lanes = _mm_mul_ps(_mm_setr_ps(0.0, 1.0, 2.0, 3.0), _mm_set1_ps(ddx));
+        for (y, chunk) in out.chunks_exact_mut(BLOCK_SIZE as usize).enumerate() {
+            let row = _mm_add_ps(_mm_set1_ps(origin + y as f32 * ddy), lanes);
+            _mm_storeu_ps(chunk.as_mut_ptr(), row);
+        }
+    }
+}
+
+/// Same as `eval_plane_sse2` operation for operation, so both give identical results.
+#[cfg_attr(target_arch = "x86_64", allow(dead_code))]
+#[inline]
+fn eval_plane_scalar(origin: f32, ddx: f32, ddy: f32, out: &mut [f32; BLOCK_PIXELS]) {
+    for (y, chunk) in out.chunks_exact_mut(BLOCK_SIZE as usize).enumerate() {
+        let row = origin + y as f32 * ddy;
+        for (x, v) in chunk.iter_mut().enumerate() {
+            *v = row + x as f32 * ddx;
+        }
+    }
+}
+
+/// Mask of the block pixels that lie inside the given pixel rectangle.
+pub fn rect_mask(block: IVec2, min: IVec2, max: IVec2) -> u16 {
+    if block.x >= min.x && block.y >= min.y && block.x + BLOCK_SIZE - 1 <= max.x && block.y + BLOCK_SIZE - 1 <= max.y {
+        return u16::MAX;
+    }
+
+    let mut mask = 0u16;
+    for y in 0..BLOCK_SIZE {
+        if block.y + y < min.y || block.y + y > max.y {
+            continue;
+        }
+        for x in 0..BLOCK_SIZE {
+            if block.x + x >= min.x && block.x + x <= max.x {
+                mask |= 1 << (y * BLOCK_SIZE + x);
+            }
+        }
+    }
+
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// Edge steps whose in-block offsets reach just below or just past `limit`, or anywhere below it.
+    fn random_steps(rng: &mut StdRng, limit: i64) -> ([i64; 3], [i64; 3]) {
+        let span = (BLOCK_SIZE - 1) as i64;
+        let mut step_x = [0; 3];
+        let mut step_y = [0; 3];
+        for i in 0..3 {
+            let total = if rng.gen_bool(0.5) { limit / span + rng.gen_range(-1..=2) } else { rng.gen_range(0..limit / span) };
+            let x = rng.gen_range(0..=total);
+            step_x[i] = if rng.gen_bool(0.5) { x } else { -x };
+            step_y[i] = if rng.gen_bool(0.5) { total - x } else { x - total };
+        }
+        (step_x, step_y)
+    }
+
+    #[test]
+    fn sse2_coverage_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let (mut partial, mut too_wide) = (0, 0);
+        for _ in 0..20000 {
+            let (step_x, step_y) = random_steps(&mut rng, LANE_LIMIT);
+            let bias = [rng.gen_range(-1..=0), rng.gen_range(-1..=0), rng.gen_range(-1..=0)];
+            let span = (BLOCK_SIZE - 1) as i64;
+            let fits_lanes = (0..3).all(|i| span * (step_x[i].abs() + step_y[i].abs()) < LANE_LIMIT);
+            let block = BlockSetup { step_x: step_x, step_y: step_y, bias: bias, fits_lanes: fits_lanes };
+
+            // Put each edge right next to a random pixel of the block, or far away from it on either side
+            let mut e = [0; 3];
+            for i in 0..3 {
+                let (x, y) = (rng.gen_range(0..BLOCK_SIZE) as i64, rng.gen_range(0..BLOCK_SIZE) as i64);
+                let near = -(x * step_x[i] + y * step_y[i] + bias[i]) + rng.gen_range(-2..=2);
+                e[i] = match rng.gen_range(0..4) {
+                    0 => rng.gen_range(-(1i64 << 40)..(1i64 << 40)),
+                    1 => near + rng.gen_range(-LANE_LIMIT..LANE_LIMIT),
+                    _ => near,
+                };
+            }
+
+            let scalar = block.coverage_scalar(e);
+            assert!(block.coverage(e) == scalar);
+            #[cfg(target_arch = "x86_64")]
+            if fits_lanes {
+                assert!(block.coverage_sse2(e) == scalar);
+            }
+            if scalar != 0 && scalar != u16::MAX {
+                partial += 1;
+            }
+            if !fits_lanes {
+                too_wide += 1;
+            }
+        }
+
+        // Plenty of blocks have an edge running through them, and plenty have steps just too large for the lanes
+        assert!(partial > 5000);
+        assert!(too_wide > 1000 && 20000 - too_wide > 1000);
+    }
+
+    #[test]
+    fn sse2_plane_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..10000 {
+            let scale = 10f32.powi(rng.gen_range(-4..6));
+            let origin = rng.gen_range(-1.0..1.0) * scale;
+            let ddx = rng.gen_range(-1.0..1.0) * scale;
+            let ddy = rng.gen_range(-1.0..1.0) * scale;
+
+            let mut scalar = [0.0; BLOCK_PIXELS];
+            eval_plane_scalar(origin, ddx, ddy, &mut scalar);
+            let mut out = [0.0; BLOCK_PIXELS];
+            eval_plane(origin, ddx, ddy, &mut out);
+            assert!(out.map(f32::to_bits) == scalar.map(f32::to_bits));
+        }
+    }
+}
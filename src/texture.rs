@@ -0,0 +1,424 @@
+use crate::vmath::*;
+
+
+/// Texel storage of a texture.
+#[allow(dead_code)]
+#[derive(Clone)]
+pub enum TextureData {
+    Rgba32F(Box<[Vec4]>),
+    Rgba8(Box<[[u8; 4]]>),
+}
+
+/// A single image of a mip chain. Row 0 is the top of the image, while v = 0 is the bottom
+/// as in OBJ texture coordinates.
+#[derive(Clone)]
+pub struct TextureLevel {
+    pub width: i32,
+    pub height: i32,
+    pub data: TextureData,
+}
+
+impl TextureLevel {
+    /// The texel at (x, y) with every channel in [0, 1].
+    #[inline]
+    pub fn texel(&self, x: i32, y: i32) -> Vec4 {
+        let idx = (y * self.width + x) as usize;
+        match &self.data {
+            TextureData::Rgba32F(texels) => texels[idx],
+            TextureData::Rgba8(texels) => {
+                let [r, g, b, a] = texels[idx];
+                Vec4::new(r as f32, g as f32, b as f32, a as f32) / 255.0
+            }
+        }
+    }
+
+    /// Half the size of this level, each texel being the average of the 2x2 texels it covers.
+    /// The last texel along an odd sized side averages the three texels left over instead.
+    fn downsample(&self) -> Self {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let rows = box_taps(y, self.height);
+            for x in 0..width {
+                let columns = box_taps(x, self.width);
+
+                let mut sum = Vec4::ZERO;
+                for &(y, row_weight) in &rows {
+                    for &(x, column_weight) in &columns {
+                        if row_weight != 0.0 && column_weight != 0.0 {
+                            sum = sum + self.texel(x, y) * (row_weight * column_weight);
+                        }
+                    }
+                }
+                texels.push(sum);
+            }
+        }
+
+        let data = match self.data {
+            TextureData::Rgba32F(_) => TextureData::Rgba32F(texels.into_boxed_slice()),
+            TextureData::Rgba8(_) => TextureData::Rgba8(texels.iter().map(|t| {
+                let scaled = *t * 255.0 + Vec4::splat(0.5);
+                [scaled.x as u8, scaled.y as u8, scaled.z as u8, scaled.w as u8]
+            }).collect()),
+        };
+
+        Self { width: width, height: height, data: data }
+    }
+}
+
+/// Source texels and their weights for texel `i` of the next level down along a side of `size` texels,
+/// unused taps have a weight of 0.
+fn box_taps(i: i32, size: i32) -> [(i32, f32); 3] {
+    if size == 1 {
+        [(0, 1.0), (0, 0.0), (0, 0.0)]
+    } else if size % 2 == 1 && i == size / 2 - 1 {
+        [(i * 2, 1.0 / 3.0), (i * 2 + 1, 1.0 / 3.0), (i * 2 + 2, 1.0 / 3.0)]
+    } else {
+        [(i * 2, 0.5), (i * 2 + 1, 0.5), (0, 0.0)]
+    }
+}
+
+/// An image to sample from, along with its mip chain once generated.
+#[derive(Clone)]
+pub struct Texture {
+    /// Level 0 is the full size image, every level after it half the size of the one before.
+    pub levels: Vec<TextureLevel>,
+}
+
+#[allow(dead_code)]
+impl Texture {
+    pub fn from_rgba32f(width: i32, height: i32, texels: Vec<Vec4>) -> Self {
+        assert_eq!(texels.len(), (width * height) as usize);
+
+        Self { levels: vec![TextureLevel { width: width, height: height, data: TextureData::Rgba32F(texels.into_boxed_slice()) }] }
+    }
+
+    pub fn from_rgba8(width: i32, height: i32, texels: Vec<[u8; 4]>) -> Self {
+        assert_eq!(texels.len(), (width * height) as usize);
+
+        Self { levels: vec![TextureLevel { width: width, height: height, data: TextureData::Rgba8(texels.into_boxed_slice()) }] }
+    }
+
+    #[inline]
+    pub fn width(&self) -> i32 {
+        self.levels[0].width
+    }
+
+    #[inline]
+    pub fn height(&self) -> i32 {
+        self.levels[0].height
+    }
+
+    /// Replaces any existing mip chain with one box filtered from level 0, down to a single texel.
+    pub fn generate_mips(&mut self) {
+        self.levels.truncate(1);
+
+        while self.levels.last().is_some_and(|level| level.width > 1 || level.height > 1) {
+            let next = self.levels[self.levels.len() - 1].downsample();
+            self.levels.push(next);
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    /// Bilinear, blends the four texels closest to the sample position.
+    Linear,
+}
+
+/// How the mip level is picked from the level of detail.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum MipFilter {
+    /// Always level 0.
+    None,
+    /// The closest level.
+    Nearest,
+    /// Blends the two closest levels, trilinear filtering together with `Filter::Linear`.
+    Linear,
+}
+
+/// How texture coordinates outside [0, 1] are mapped back onto the texture.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum AddressMode {
+    Repeat,
+    /// Coordinates are clamped to the edge texels.
+    ClampToEdge,
+    /// Repeats the texture, flipping every other copy.
+    MirroredRepeat,
+    /// Texels outside the texture read as the sampler's border colour.
+    ClampToBorder,
+}
+
+impl AddressMode {
+    /// Maps a texel index onto [0, size), None if it falls on the border.
+    #[inline]
+    fn apply(self, i: i32, size: i32) -> Option<i32> {
+        match self {
+            AddressMode::Repeat => Some(i.rem_euclid(size)),
+            AddressMode::ClampToEdge => Some(clamp(i, 0, size - 1)),
+            AddressMode::MirroredRepeat => {
+                let m = i.rem_euclid(size * 2);
+                Some(if m < size { m } else { size * 2 - 1 - m })
+            }
+            AddressMode::ClampToBorder => if i >= 0 && i < size { Some(i) } else { None },
+        }
+    }
+
+    /// Moves a texel coordinate into the first period of the texture, or just around it when clamping,
+    /// so it addresses the same texels but fits an i32 with room to step to the next texel.
+    #[inline]
+    fn reduce(self, x: f32, size: i32) -> f32 {
+        let size = size as f32;
+        match self {
+            AddressMode::Repeat => x - (x / size).floor() * size,
+            AddressMode::MirroredRepeat => x - (x / (size * 2.0)).floor() * size * 2.0,
+            AddressMode::ClampToEdge | AddressMode::ClampToBorder => clamp(x, -1.0, size),
+        }
+    }
+}
+
+/// How a texture is read, kept apart from the texture so the same image can be sampled in different ways.
+#[derive(Clone, Copy)]
+pub struct Sampler {
+    /// Used when a texel covers less than a pixel.
+    pub min_filter: Filter,
+    /// Used when a texel covers a pixel or more.
+    pub mag_filter: Filter,
+    pub mip_filter: MipFilter,
+    pub address_u: AddressMode,
+    pub address_v: AddressMode,
+    pub border_color: Vec4,
+    /// Most probes taken along the longer axis of the pixel footprint, 1 disables anisotropic filtering.
+    pub max_anisotropy: u32,
+}
+
+#[allow(dead_code)]
+impl Sampler {
+    pub const NEAREST: Self = Self::new(Filter::Nearest, Filter::Nearest, MipFilter::None);
+    pub const LINEAR: Self = Self::new(Filter::Linear, Filter::Linear, MipFilter::None);
+    pub const TRILINEAR: Self = Self::new(Filter::Linear, Filter::Linear, MipFilter::Linear);
+
+    /// A sampler repeating the texture on both axes.
+    pub const fn new(min_filter: Filter, mag_filter: Filter, mip_filter: MipFilter) -> Self {
+        Self {
+            min_filter: min_filter,
+            mag_filter: mag_filter,
+            mip_filter: mip_filter,
+            address_u: AddressMode::Repeat,
+            address_v: AddressMode::Repeat,
+            border_color: Vec4::ZERO,
+            max_anisotropy: 1,
+        }
+    }
+
+    /// Trilinear filtering with up to `max_anisotropy` probes, clamped to [2, 16].
+    pub fn anisotropic(max_anisotropy: u32) -> Self {
+        Self { max_anisotropy: clamp(max_anisotropy, 2, 16), ..Self::TRILINEAR }
+    }
+
+    /// The same address mode on both axes.
+    pub fn with_address_mode(self, mode: AddressMode) -> Self {
+        Self { address_u: mode, address_v: mode, ..self }
+    }
+
+    /// Samples level 0 with the magnification filter.
+    pub fn sample(&self, texture: &Texture, uv: Vec2) -> Vec4 {
+        self.sample_level(&texture.levels[0], uv, self.mag_filter)
+    }
+
+    /// Samples with the level of detail picked from the change in texture coordinates per pixel right and up.
+    pub fn sample_grad(&self, texture: &Texture, uv: Vec2, ddx: Vec2, ddy: Vec2) -> Vec4 {
+        // The footprint of the pixel in level 0 texels
+        let size = Vec2::new(texture.width() as f32, texture.height() as f32);
+        let texels_x = Vec2::new(ddx.x * size.x, ddx.y * size.y);
+        let texels_y = Vec2::new(ddy.x * size.x, ddy.y * size.y);
+        let len_x = dot(texels_x, texels_x).sqrt();
+        let len_y = dot(texels_y, texels_y).sqrt();
+
+        if self.max_anisotropy <= 1 || self.mip_filter == MipFilter::None {
+            return self.sample_lod(texture, uv, len_x.max(len_y).log2());
+        }
+
+        // Probes spread along the major axis, each filtered over the footprint width divided among them
+        let (major, major_len, minor_len) = if len_x >= len_y { (ddx, len_x, len_y) } else { (ddy, len_y, len_x) };
+        let (probes, lod) = self.anisotropic_probes(major_len, minor_len);
+
+        let mut sum = Vec4::ZERO;
+        for i in 0..probes {
+            let offset = (i as f32 + 0.5) / probes as f32 - 0.5;
+            sum = sum + self.sample_lod(texture, uv + major * offset, lod);
+        }
+
+        sum / probes as f32
+    }
+
+    /// Number of probes for a footprint whose axes are `major_len` and `minor_len` texels long,
+    /// and the level of detail to sample each of them at.
+    fn anisotropic_probes(&self, major_len: f32, minor_len: f32) -> (u32, f32) {
+        let ratio = clamp(major_len / minor_len.max(f32::MIN_POSITIVE), 1.0, self.max_anisotropy as f32);
+
+        (ratio.ceil() as u32, (major_len / ratio).log2())
+    }
+
+    fn sample_lod(&self, texture: &Texture, uv: Vec2, lod: f32) -> Vec4 {
+        let filter = if lod > 0.0 { self.min_filter } else { self.mag_filter };
+
+        let max_level = (texture.levels.len() - 1) as f32;
+        let lod = clamp(lod, 0.0, max_level);
+
+        match self.mip_filter {
+            MipFilter::None => self.sample_level(&texture.levels[0], uv, filter),
+            MipFilter::Nearest => self.sample_level(&texture.levels[lod.round() as usize], uv, filter),
+            MipFilter::Linear => {
+                let level = lod.floor();
+                let t = lod - level;
+                let fine = self.sample_level(&texture.levels[level as usize], uv, filter);
+                if t == 0.0 {
+                    return fine;
+                }
+
+                let coarse = self.sample_level(&texture.levels[level as usize + 1], uv, filter);
+                fine * (1.0 - t) + coarse * t
+            }
+        }
+    }
+
+    #[inline]
+    fn texel(&self, level: &TextureLevel, x: i32, y: i32) -> Vec4 {
+        match (self.address_u.apply(x, level.width), self.address_v.apply(y, level.height)) {
+            (Some(x), Some(y)) => level.texel(x, y),
+            _ => self.border_color,
+        }
+    }
+
+    fn sample_level(&self, level: &TextureLevel, uv: Vec2, filter: Filter) -> Vec4 {
+        // Texel centres are at half integers
+        let x = self.address_u.reduce(uv.x * level.width as f32 - 0.5, level.width);
+        let y = self.address_v.reduce((1.0 - uv.y) * level.height as f32 - 0.5, level.height);
+
+        match filter {
+            Filter::Nearest => self.texel(level, (x + 0.5).floor() as i32, (y + 0.5).floor() as i32),
+            Filter::Linear => {
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let fx = x - x0;
+                let fy = y - y0;
+
+                let left = x0 as i32;
+                let top = y0 as i32;
+
+                let upper = self.texel(level, left, top) * (1.0 - fx) + self.texel(level, left + 1, top) * fx;
+                let lower = self.texel(level, left, top + 1) * (1.0 - fx) + self.texel(level, left + 1, top + 1) * fx;
+
+                upper * (1.0 - fy) + lower * fy
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn odd_sizes_keep_the_last_texel_when_downsampled() {
+        let black = Vec4::new(0.0, 0.0, 0.0, 1.0);
+        let white = Vec4::ONE;
+        let mut texture = Texture::from_rgba32f(3, 1, vec![black, black, white]);
+        texture.generate_mips();
+
+        let third = texture.levels[1].texel(0, 0);
+        assert!((third.x - 1.0 / 3.0).abs() < 1e-6 && (third.w - 1.0).abs() < 1e-6);
+
+        // 5x3 with a white last column and row, the corner texel covers 3x3 texels of which 5 are white
+        let texels = (0..15).map(|i| if i % 5 == 4 || i / 5 == 2 { white } else { black }).collect();
+        let mut texture = Texture::from_rgba32f(5, 3, texels);
+        texture.generate_mips();
+
+        assert!(texture.levels[1].width == 2 && texture.levels[1].height == 1);
+        assert!((texture.levels[1].texel(0, 0).x - 1.0 / 3.0).abs() < 1e-6);
+        assert!((texture.levels[1].texel(1, 0).x - 5.0 / 9.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn huge_coordinates_wrap_instead_of_overflowing() {
+        let texels = (0..1024 * 4).map(|i| Vec4::splat((i % 1024) as f32 / 1023.0)).collect();
+        let texture = Texture::from_rgba32f(1024, 4, texels);
+
+        // 3e6 is a whole number of repeats, at that size the half texel offset rounds away and it lands on the first texel centre
+        let uv = Vec2::new(3.0e6, 0.5);
+        let expected = Sampler::LINEAR.sample_level(&texture.levels[0], Vec2::new(0.5 / 1024.0, 0.5), Filter::Linear);
+        assert!(Sampler::LINEAR.sample_level(&texture.levels[0], uv, Filter::Linear) == expected);
+
+        let nearest = |sampler: Sampler, u: f32, v: f32| sampler.sample_level(&texture.levels[0], Vec2::new(u, v), Filter::Nearest);
+
+        let edge = Sampler::NEAREST.with_address_mode(AddressMode::ClampToEdge);
+        assert!(nearest(edge, 3.0e9, 0.5) == Vec4::ONE && nearest(edge, f32::MAX, 0.5) == Vec4::ONE);
+        assert!(nearest(edge, -3.0e9, 0.5) == Vec4::ZERO && nearest(edge, f32::MIN, 0.5) == Vec4::ZERO);
+
+        // A quarter into the flipped copy on either side of zero, which mirrors back onto texel 1023 - 256
+        let mirrored = Sampler::NEAREST.with_address_mode(AddressMode::MirroredRepeat);
+        let texel_767 = Vec4::splat(767.0 / 1023.0);
+        assert!(nearest(mirrored, 1.0e6 + 1.25, 0.5) == texel_767);
+        assert!(nearest(mirrored, -1.0e6 + 1.25, 0.5) == texel_767);
+
+        let mut border = Sampler::NEAREST.with_address_mode(AddressMode::ClampToBorder);
+        border.border_color = Vec4::new(1.0, 0.0, 1.0, 1.0);
+        for (u, v) in [(3.0e9, 0.5), (-3.0e9, 0.5), (0.5, 3.0e9), (f32::MAX, f32::MIN)] {
+            assert!(nearest(border, u, v) == border.border_color);
+        }
+
+        for mode in [AddressMode::ClampToEdge, AddressMode::MirroredRepeat, AddressMode::ClampToBorder] {
+            let sampler = Sampler::LINEAR.with_address_mode(mode);
+            for uv in [Vec2::new(3.0e9, -3.0e9), Vec2::new(-3.0e9, 3.0e9), Vec2::new(f32::MAX, f32::MIN)] {
+                sampler.sample_level(&texture.levels[0], uv, Filter::Linear);
+            }
+        }
+    }
+
+    #[test]
+    fn anisotropic_probes_follow_the_footprint() {
+        let sampler = Sampler::anisotropic(16);
+        // (major, minor) texels, then the probes and the level of detail of each
+        let expected = [
+            ((3.0, 3.0), (1, 3f32.log2())),
+            ((5.0, 2.0), (3, 1.0)),
+            ((16.0, 2.0), (8, 1.0)),
+            // Limited to 16 probes, each one covers more
+            ((64.0, 2.0), (16, 2.0)),
+            ((8.0, 0.0), (16, -1.0)),
+        ];
+        for ((major, minor), (probes, lod)) in expected {
+            let (actual_probes, actual_lod) = sampler.anisotropic_probes(major, minor);
+            assert!(actual_probes == probes && (actual_lod - lod).abs() < 1e-6);
+        }
+
+        let (probes, lod) = Sampler::anisotropic(4).anisotropic_probes(16.0, 1.0);
+        assert!(probes == 4 && lod == 2.0);
+    }
+
+    #[test]
+    fn anisotropic_filtering_keeps_detail_across_a_stretched_footprint() {
+        // One texel wide black and white stripes, running along v, every mip below the first is grey
+        let stripes = (0..64 * 64).map(|i| if i % 2 == 1 { Vec4::ONE } else { Vec4::new(0.0, 0.0, 0.0, 1.0) }).collect();
+        let mut texture = Texture::from_rgba32f(64, 64, stripes);
+        texture.generate_mips();
+
+        // The centre of a white column, with a footprint one texel across it and sixteen along it
+        let uv = Vec2::new(1.5 / 64.0, 0.5);
+        let ddx = Vec2::new(1.0 / 64.0, 0.0);
+        let ddy = Vec2::new(0.0, 16.0 / 64.0);
+
+        let trilinear = Sampler::TRILINEAR.sample_grad(&texture, uv, ddx, ddy);
+        assert!(trilinear == Vec4::new(0.5, 0.5, 0.5, 1.0));
+
+        let anisotropic = Sampler::anisotropic(16).sample_grad(&texture, uv, ddx, ddy);
+        assert!(anisotropic == Vec4::ONE);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::vmath::*;
+
+
+/// Width and height of a screen tile in pixels.
+pub const TILE_SIZE: i32 = 32;
+
+/// Per tile lists of the triangles overlapping it, in submission order.
+pub struct TileBins {
+    pub tiles_x: i32,
+    pub tiles_y: i32,
+    bins: Vec<Vec<u32>>,
+}
+
+impl TileBins {
+    pub fn new(width: i32, height: i32) -> Self {
+        let tiles_x = (width + TILE_SIZE - 1) / TILE_SIZE;
+        let tiles_y = (height + TILE_SIZE - 1) / TILE_SIZE;
+
+        Self {
+            tiles_x: tiles_x,
+            tiles_y: tiles_y,
+            bins: (0..tiles_x * tiles_y).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Empties every bin but keeps their allocations around for the next draw.
+    pub fn clear(&mut self) {
+        self.bins.iter_mut().for_each(|bin| bin.clear());
+    }
+
+    /// Adds a triangle to every tile its pixel bounds touch.
+    pub fn insert(&mut self, triangle: u32, bound_min: IVec2, bound_max: IVec2) {
+        let start_x = clamp(bound_min.x / TILE_SIZE, 0, self.tiles_x - 1);
+        let start_y = clamp(bound_min.y / TILE_SIZE, 0, self.tiles_y - 1);
+        let end_x = clamp(bound_max.x / TILE_SIZE, 0, self.tiles_x - 1);
+        let end_y = clamp(bound_max.y / TILE_SIZE, 0, self.tiles_y - 1);
+
+        for tile_y in start_y..=end_y {
+            for tile_x in start_x..=end_x {
+                self.bins[(tile_y * self.tiles_x + tile_x) as usize].push(triangle);
+            }
+        }
+    }
+
+    /// Whether anything was binned into a tile of the given row.
+    pub fn row_has_work(&self, tile_y: i32) -> bool {
+        let start = (tile_y * self.tiles_x) as usize;
+        self.bins[start..start + self.tiles_x as usize].iter().any(|bin| !bin.is_empty())
+    }
+
+    #[inline]
+    pub fn get(&self, tile_x: i32, tile_y: i32) -> &[u32] {
+        &self.bins[(tile_y * self.tiles_x + tile_x) as usize]
+    }
+}
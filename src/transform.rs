@@ -141,8 +141,6 @@ impl Transform for CameraTransform {
 pub struct WorldToScreenTransform {
     // given
     fov: f32,
-    width: f32,
-    height: f32,
     z_near: f32,
     z_far: f32,
     ar: f32,
@@ -155,8 +153,6 @@ impl WorldToScreenTransform {
     pub const fn new(fov_degrees: f32, width: f32, height: f32, z_near: f32, z_far: f32) -> Self {
         Self {
             fov: fov_degrees,
-            width: width,
-            height: height,
             z_near: z_near,
             z_far: z_far,
             tan_half_fov: 0.0,
@@ -165,29 +161,57 @@ impl WorldToScreenTransform {
     }
 }
 
-impl Transform for WorldToScreenTransform {
-    fn calculate_transform(&mut self) {
+impl WorldToScreenTransform {
+    pub fn calculate_transform(&mut self) {
         self.tan_half_fov = f32::tan((self.fov/2.0).to_radians());
     }
 
-    fn apply_transform(&self, p: &mut Vec3) {
+    /// Projects a view space point into homogeneous clip space. Inside the frustum
+    /// -w <= x <= w, -w <= y <= w and 0 <= z <= w, with w being the view depth.
+    pub fn project(&self, p: Vec3) -> Vec4 {
         assert!(self.tan_half_fov != 0.0);
 
-        let f = (self.height / 2.0) / self.tan_half_fov;
+        Vec4::new(
+            p.x / (self.ar * self.tan_half_fov),
+            p.y / self.tan_half_fov,
+            (self.z_far * (p.z - self.z_near)) / (self.z_far - self.z_near),
+            p.z,
+        )
+    }
+}
 
-        p.x = (p.x * f) / p.z;
-        p.y = (p.y * f) / p.z;
+/// Maps normalized device coordinates onto a rectangle of the render target, in pixels from the
+/// bottom left corner, and depth onto [min_depth, max_depth].
+#[derive(Clone, Copy)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub min_depth: f32,
+    pub max_depth: f32,
+}
 
-        p.x += self.width / 2.0;
-        p.y += self.height / 2.0;
+impl Viewport {
+    pub const fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }
+    }
 
-        //let top = self.z_near * self.tan_half_fov;
-        //let right = top * self.ar;
+    /// Perspective divide followed by the mapping to pixel coordinates, z ends up as depth.
+    pub fn to_screen(self, clip: Vec4) -> Vec3 {
+        let inv_w = 1.0 / clip.w;
 
-        //p.x = p.x / (p.z / self.z_near);
-        //p.y = p.y / (p.z / self.z_near);
-        //p.x = map(p.x, -right, right, 0.0, self.width);
-        //p.y = map(p.y, -top, top, 0.0, self.height);
+        Vec3::new(
+            self.x + (clip.x * inv_w + 1.0) * self.width / 2.0,
+            self.y + (clip.y * inv_w + 1.0) * self.height / 2.0,
+            self.min_depth + clip.z * inv_w * (self.max_depth - self.min_depth),
+        )
     }
 }
-
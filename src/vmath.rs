@@ -22,20 +22,12 @@ pub fn dot(a: Vec2, b: Vec2) -> f32 {
     a.x * b.x + a.y * b.y
 }
 
-pub fn is_point_on_rightside_of_line(point: Vec2, a: Vec2, b: Vec2) -> bool {
+/// Twice the signed area of the triangle (a, b, p).
+pub fn edge_function(a: Vec2, b: Vec2, p: Vec2) -> f32 {
     let ab = b - a;
-    let rotated = Vec2::new(ab.y, -ab.x);
+    let ap = p - a;
 
-    let ap = point - a;
-
-    dot(ap, rotated) <= 0.0
-}
-
-pub fn is_point_in_triangle(point: Vec2, a: Vec2, b: Vec2, c: Vec2) -> bool {
-    let check0 = is_point_on_rightside_of_line(point, a, b);
-    let check1 = is_point_on_rightside_of_line(point, b, c);
-    let check2 = is_point_on_rightside_of_line(point, c, a);
-    return check0 && check1 && check2;
+    ab.x * ap.y - ab.y * ap.x
 }
 
 #[derive(Clone, PartialEq)]
@@ -107,6 +99,15 @@ impl Vec2 {
     }
 }
 
+impl Add for Vec2 {
+    type Output = Vec2;
+
+    #[inline]
+    fn add(self, rhs: Vec2) -> Self::Output {
+        Self { x: self.x + rhs.x, y: self.y + rhs.y }
+    }
+}
+
 impl Sub for Vec2 {
     type Output = Vec2;
 
@@ -116,6 +117,15 @@ impl Sub for Vec2 {
     }
 }
 
+impl Mul<f32> for Vec2 {
+    type Output = Vec2;
+
+    #[inline]
+    fn mul(self, rhs: f32) -> Self::Output {
+        Self { x: self.x * rhs, y: self.y * rhs }
+    }
+}
+
 impl Index<usize> for Vec2 {
     type Output = f32;
 
@@ -177,6 +187,10 @@ impl Vec3 {
         self / self.len()
     }
 
+    pub fn dot(self, rhs: Vec3) -> f32 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
     pub fn v4(self) -> Vec4 {
         Vec4::new(self.x, self.y, self.z, 1.0)
     }
@@ -229,6 +243,20 @@ impl Add<Vec3> for Vec3 {
 
 }
 
+impl Sub<Vec3> for Vec3 {
+    type Output = Vec3;
+
+    #[inline]
+    fn sub(self, rhs: Vec3) -> Self {
+        Self {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z,
+        }
+    }
+
+}
+
 impl Div<f32> for Vec3 {
     type Output = Vec3;
 
@@ -303,6 +331,38 @@ impl Vec4 {
     pub const fn splat(v: f32) -> Self {
         Self { x: v, y: v, z: v, w: v }
     }
+
+    pub const fn xyz(self) -> Vec3 {
+        Vec3 { x: self.x, y: self.y, z: self.z }
+    }
+}
+
+impl Add<Vec4> for Vec4 {
+    type Output = Vec4;
+
+    #[inline]
+    fn add(self, rhs: Vec4) -> Self {
+        Self {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+            z: self.z + rhs.z,
+            w: self.w + rhs.w,
+        }
+    }
+}
+
+impl Sub<Vec4> for Vec4 {
+    type Output = Vec4;
+
+    #[inline]
+    fn sub(self, rhs: Vec4) -> Self {
+        Self {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z,
+            w: self.w - rhs.w,
+        }
+    }
 }
 
 impl Mul<f32> for Vec4 {
@@ -0,0 +1,127 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+
+/// A job borrowed from `WorkerPool::run` with its lifetime erased, it is never used after `run` returns.
+type Job = &'static (dyn Fn() + Sync);
+
+struct State {
+    job: Option<Job>,
+    /// Bumped for every job, so each worker runs it exactly once.
+    generation: u64,
+    /// Workers still running the current job.
+    busy: usize,
+    panicked: bool,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    start: Condvar,
+    done: Condvar,
+}
+
+/// Threads kept around between draws, so a draw doesn't pay for spawning them.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { job: None, generation: 0, busy: 0, panicked: false, shutdown: false }),
+            start: Condvar::new(),
+            done: Condvar::new(),
+        });
+        let threads = (0..workers)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || work(&shared))
+            })
+            .collect();
+
+        Self { shared: shared, threads: threads }
+    }
+
+    /// Number of worker threads, not counting the one calling `run`.
+    pub fn workers(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Calls `job` on every worker and on the calling thread at once, and returns once all of them are done.
+    pub fn run(&self, job: &(dyn Fn() + Sync)) {
+        // SAFETY: only the lifetime changes, and `Finish` doesn't let this function return or unwind
+        // before every worker is done with the job
+        let erased = unsafe { std::mem::transmute::<&(dyn Fn() + Sync), Job>(job) };
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.job = Some(erased);
+            state.generation += 1;
+            state.busy = self.threads.len();
+        }
+        self.shared.start.notify_all();
+
+        let finish = Finish(&self.shared);
+        job();
+        drop(finish);
+
+        let mut state = self.shared.state.lock().unwrap();
+        if state.panicked {
+            state.panicked = false;
+            panic!("A worker thread panicked");
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.start.notify_all();
+
+        for thread in self.threads.drain(..) {
+            _ = thread.join();
+        }
+    }
+}
+
+/// Waits for the workers to finish the current job when dropped.
+struct Finish<'a>(&'a Shared);
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        while state.busy > 0 {
+            state = self.0.done.wait(state).unwrap();
+        }
+        state.job = None;
+    }
+}
+
+fn work(shared: &Shared) {
+    let mut generation = 0;
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            while !state.shutdown && state.generation == generation {
+                state = shared.start.wait(state).unwrap();
+            }
+            if state.shutdown {
+                return;
+            }
+            generation = state.generation;
+            state.job.unwrap()
+        };
+
+        // A panicking job must still count as done, or `run` would wait for it forever
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+
+        let mut state = shared.state.lock().unwrap();
+        state.panicked |= result.is_err();
+        state.busy -= 1;
+        if state.busy == 0 {
+            shared.done.notify_all();
+        }
+    }
+}